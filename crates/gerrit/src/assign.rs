@@ -0,0 +1,111 @@
+use octabot_rust_sdk::{Error, KeyValue, PluginError};
+use serde::{Deserialize, Serialize};
+
+use crate::{GerritPlugin, ProjectFilter, Review, DEFAULT_MAX_CHANGES};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum AssignStrategy {
+  #[default]
+  RoundRobin,
+  LeastLoaded,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AssignOptions {
+  query: String,
+  #[serde(flatten)]
+  project_filter: ProjectFilter,
+  pool: Vec<String>,
+  #[serde(default)]
+  strategy: AssignStrategy,
+  #[serde(default)]
+  ignored_reviewers: Vec<String>,
+  max_changes: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct AssignState {
+  next: usize,
+}
+
+impl GerritPlugin {
+  /// Checks whether the change has reviewers apart from the owner and ignored accounts, e.g. CI bots.
+  fn has_reviewers(review: &Review, ignored: &[String]) -> bool {
+    review.reviewers.get("REVIEWER").is_some_and(|reviewers| {
+      reviewers.iter().any(|reviewer| {
+        reviewer.username != review.owner.username && !ignored.iter().any(|account| reviewer.is(account))
+      })
+    })
+  }
+
+  /// Counts open changes the account is a reviewer of.
+  fn reviewer_load(account: &str) -> Result<usize, PluginError> {
+    let changes = GerritPlugin::query_changes(&format!("is:open reviewer:{}", account), DEFAULT_MAX_CHANGES)?;
+
+    Ok(changes.len())
+  }
+
+  /// Adds a reviewer from the pool to every found change without reviewers, the change owner is never chosen.
+  pub(crate) fn assign_reviewers(task_id: &str, options: &AssignOptions) -> Result<(), Error> {
+    if options.pool.is_empty() {
+      return Err(PluginError::Other("Reviewer pool is empty".to_string()).into());
+    }
+
+    let query = GerritPlugin::build_query(&options.query, &options.project_filter)?;
+    let max_changes = options.max_changes.unwrap_or(DEFAULT_MAX_CHANGES);
+    let reviews = GerritPlugin::query_changes(&query, max_changes)?;
+
+    let unassigned = reviews
+      .iter()
+      .filter(|review| !GerritPlugin::has_reviewers(review, &options.ignored_reviewers))
+      .collect::<Vec<_>>();
+    if unassigned.is_empty() {
+      return Ok(());
+    }
+
+    let keyvalue = KeyValue::open()?;
+    let mut state = GerritPlugin::load_state::<AssignState>(&keyvalue, task_id, "assign")?;
+
+    let mut load = vec![];
+    if let AssignStrategy::LeastLoaded = options.strategy {
+      for account in &options.pool {
+        load.push(GerritPlugin::reviewer_load(account)?);
+      }
+    }
+
+    let mut result = Ok(());
+    for review in unassigned {
+      let candidates = (0..options.pool.len())
+        .filter(|i| !review.owner.is(&options.pool[*i]))
+        .collect::<Vec<_>>();
+
+      let chosen = match options.strategy {
+        AssignStrategy::RoundRobin => candidates
+          .iter()
+          .copied()
+          .find(|i| *i >= state.next % options.pool.len())
+          .or(candidates.first().copied()),
+        AssignStrategy::LeastLoaded => candidates.iter().copied().min_by_key(|i| load[*i]),
+      };
+
+      let Some(chosen) = chosen else {
+        continue;
+      };
+
+      if let Err(e) = GerritPlugin::add_reviewer(&review.id, &options.pool[chosen]) {
+        result = Err(e);
+        break;
+      }
+
+      state.next = chosen + 1;
+      if let Some(load) = load.get_mut(chosen) {
+        *load += 1;
+      }
+    }
+
+    GerritPlugin::save_state(&keyvalue, task_id, "assign", &state)?;
+
+    Ok(result?)
+  }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use octabot_rust_sdk::{PluginError, PluginResult};
+use serde::{Deserialize, Serialize};
+use strfmt::strfmt;
+use waki::Method;
+
+use crate::notification::Notification;
+use crate::{gerrit_timestamp, GerritPlugin, ProjectFilter, Review, User, CONFIG, DEFAULT_MAX_CHANGES};
+
+const DEFAULT_SNIPPET_LENGTH: usize = 80;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CommentOptions {
+  query: String,
+  #[serde(flatten)]
+  project_filter: ProjectFilter,
+  template: String,
+  review_template: String,
+  thread_template: String,
+  snippet_length: Option<usize>,
+  max_changes: Option<usize>,
+  notification: Option<Notification>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Comment {
+  id: String,
+  in_reply_to: Option<String>,
+  line: Option<i32>,
+  #[serde(default)]
+  author: User,
+  #[serde(default)]
+  message: String,
+  #[serde(with = "gerrit_timestamp")]
+  updated: DateTime<Utc>,
+  unresolved: Option<bool>,
+}
+
+/// Comment thread with the file it belongs to, comments are ordered by time.
+struct Thread<'a> {
+  file: &'a str,
+  comments: Vec<&'a Comment>,
+}
+
+impl Thread<'_> {
+  /// A thread is unresolved when its latest comment is.
+  fn is_unresolved(&self) -> bool {
+    self.comments.last().is_some_and(|c| c.unresolved == Some(true))
+  }
+
+  fn vars(&self, snippet_length: usize, now: DateTime<Utc>) -> HashMap<String, String> {
+    let first = self.comments[0];
+    let last = self.comments[self.comments.len() - 1];
+
+    let line = last.message.lines().next().unwrap_or_default();
+    let mut snippet = line.chars().take(snippet_length).collect::<String>();
+    if line.chars().count() > snippet_length || last.message.lines().count() > 1 {
+      snippet.push('…');
+    }
+
+    HashMap::from([
+      ("file".to_string(), self.file.to_string()),
+      (
+        "line".to_string(),
+        first.line.map(|l| l.to_string()).unwrap_or_default(),
+      ),
+      ("author".to_string(), first.author.name.clone()),
+      ("last_author".to_string(), last.author.name.clone()),
+      ("message".to_string(), snippet),
+      ("comments".to_string(), self.comments.len().to_string()),
+      ("age".to_string(), GerritPlugin::format_age(now - first.updated)),
+    ])
+  }
+}
+
+impl GerritPlugin {
+  fn change_comments(review: &Review) -> Result<BTreeMap<String, Vec<Comment>>, PluginError> {
+    let path = format!("changes/{}/comments", GerritPlugin::encode_id(&review.id));
+    let client = GerritPlugin::request(Method::Get, &path)?;
+
+    GerritPlugin::send(client)
+  }
+
+  /// Collects comment threads by following `in_reply_to` up to the root comment.
+  fn comment_threads(comments: &BTreeMap<String, Vec<Comment>>) -> Vec<Thread<'_>> {
+    fn root<'a>(parents: &HashMap<&'a str, Option<&'a str>>, mut id: &'a str) -> &'a str {
+      while let Some(Some(parent)) = parents.get(id) {
+        id = parent;
+      }
+      id
+    }
+
+    let mut threads = vec![];
+    for (file, comments) in comments {
+      let parents = comments
+        .iter()
+        .map(|c| (c.id.as_str(), c.in_reply_to.as_deref()))
+        .collect::<HashMap<_, _>>();
+
+      let mut file_threads: Vec<(&str, Vec<&Comment>)> = vec![];
+      for comment in comments {
+        let root = root(&parents, &comment.id);
+        match file_threads.iter_mut().find(|(r, _)| *r == root) {
+          Some((_, thread)) => thread.push(comment),
+          None => file_threads.push((root, vec![comment])),
+        }
+      }
+
+      for (_, mut comments) in file_threads {
+        comments.sort_by_key(|c| c.updated);
+        threads.push(Thread { file, comments });
+      }
+    }
+
+    threads
+  }
+
+  /// Sends every owner a digest of unresolved comment threads on their changes.
+  pub(crate) fn process_comments(task_id: &str, options: &CommentOptions) -> Result<Vec<PluginResult>, PluginError> {
+    let config = CONFIG
+      .lock()
+      .map_err(|e| PluginError::ConfigLock(e.to_string()))?
+      .clone()
+      .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
+
+    let query = GerritPlugin::build_query(&options.query, &options.project_filter)?;
+    let max_changes = options.max_changes.unwrap_or(DEFAULT_MAX_CHANGES);
+    let reviews = GerritPlugin::query_changes(&query, max_changes)?;
+
+    let now = Utc::now();
+    let snippet_length = options.snippet_length.unwrap_or(DEFAULT_SNIPPET_LENGTH);
+    let mut owners: BTreeMap<&str, String> = BTreeMap::new();
+    for review in reviews.iter().filter(|r| r.unresolved_comment_count > 0) {
+      let Some(email) = review.owner.notification_email() else {
+        continue;
+      };
+
+      let comments = GerritPlugin::change_comments(review)?;
+      let threads = GerritPlugin::comment_threads(&comments);
+
+      let mut entry = String::new();
+      for thread in threads.iter().filter(|t| t.is_unresolved()) {
+        let line = strfmt(&options.thread_template, &thread.vars(snippet_length, now))
+          .map_err(|e| PluginError::Other(format!("Failed to format thread template: {}", e)))?;
+        entry.push_str(&line);
+      }
+      if entry.is_empty() {
+        continue;
+      }
+
+      let message = owners.entry(email).or_insert_with(|| options.template.clone());
+      message.push_str(&GerritPlugin::format_review_message(
+        review,
+        &options.review_template,
+        &config,
+      )?);
+      message.push_str(&entry);
+    }
+
+    owners
+      .into_iter()
+      .map(|(email, message)| GerritPlugin::direct_notification(task_id, options.notification.as_ref(), email, message))
+      .collect()
+  }
+}
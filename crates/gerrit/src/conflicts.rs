@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+use octabot_rust_sdk::{Error, KeyValue, PluginError, PluginResult};
+use serde::{Deserialize, Serialize};
+
+use crate::notification::Notification;
+use crate::{GerritPlugin, ProjectFilter, Review, CONFIG, DEFAULT_MAX_CHANGES};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ConflictOptions {
+  query: String,
+  #[serde(flatten)]
+  project_filter: ProjectFilter,
+  template: String,
+  review_template: String,
+  max_changes: Option<usize>,
+  notification: Option<Notification>,
+}
+
+impl GerritPlugin {
+  /// Notifies owners of changes that turned from mergeable to conflicting since the last run.
+  pub(crate) fn process_conflicts(task_id: &str, options: &ConflictOptions) -> Result<Vec<PluginResult>, Error> {
+    let config = CONFIG
+      .lock()
+      .map_err(|e| PluginError::ConfigLock(e.to_string()))?
+      .clone()
+      .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
+
+    let query = GerritPlugin::build_query(&options.query, &options.project_filter)?;
+    let max_changes = options.max_changes.unwrap_or(DEFAULT_MAX_CHANGES);
+    let reviews = GerritPlugin::query_changes_with(&query, max_changes, &["MERGEABLE"])?;
+
+    let keyvalue = KeyValue::open()?;
+    let previous: BTreeMap<i32, bool> = GerritPlugin::load_state(&keyvalue, task_id, "mergeable")?;
+
+    // Changes without the `mergeable` field, e.g. when Gerrit doesn't compute it, are not tracked
+    let mut current = BTreeMap::new();
+    let mut owners: BTreeMap<&str, Vec<&Review>> = BTreeMap::new();
+    for review in &reviews {
+      let Some(mergeable) = review.mergeable else {
+        continue;
+      };
+
+      if !mergeable && previous.get(&review._number) == Some(&true) {
+        if let Some(email) = review.owner.notification_email() {
+          owners.entry(email).or_default().push(review);
+        }
+      }
+      current.insert(review._number, mergeable);
+    }
+
+    GerritPlugin::save_state(&keyvalue, task_id, "mergeable", &current)?;
+
+    let mut notifications = vec![];
+    for (email, reviews) in owners {
+      let mut message = options.template.clone();
+      for review in reviews {
+        message.push_str(&GerritPlugin::format_review_message(
+          review,
+          &options.review_template,
+          &config,
+        )?);
+      }
+
+      notifications.push(GerritPlugin::direct_notification(
+        task_id,
+        options.notification.as_ref(),
+        email,
+        message,
+      )?);
+    }
+
+    Ok(notifications)
+  }
+}
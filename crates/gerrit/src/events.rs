@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use octabot_rust_sdk::{PluginError, PluginResult};
+use serde::{Deserialize, Serialize};
+use strfmt::strfmt;
+
+use crate::{notification::Notification, GerritPlugin, User, CONFIG};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EventRoute {
+  channel: String,
+  topic: String,
+  template: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EventOptions {
+  event: serde_json::Value,
+  routes: HashMap<String, EventRoute>,
+  notification: Option<Notification>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct EventChange {
+  project: String,
+  branch: String,
+  id: String,
+  number: i32,
+  subject: String,
+  owner: User,
+  url: String,
+  topic: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EventPatchSet {
+  number: i32,
+  revision: String,
+  size_insertions: Option<i32>,
+  size_deletions: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EventApproval {
+  #[serde(rename = "type")]
+  label: String,
+  value: String,
+  old_value: Option<String>,
+}
+
+/// Event in the format of `gerrit stream-events` and the webhooks plugin.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GerritEvent {
+  #[serde(rename = "type")]
+  kind: String,
+  change: EventChange,
+  patch_set: Option<EventPatchSet>,
+  uploader: Option<User>,
+  author: Option<User>,
+  submitter: Option<User>,
+  adder: Option<User>,
+  remover: Option<User>,
+  reviewer: Option<User>,
+  #[serde(default)]
+  approvals: Vec<EventApproval>,
+  comment: Option<String>,
+}
+
+impl EventApproval {
+  /// Formats the vote, e.g. "Code-Review+2".
+  fn summary(&self) -> String {
+    if self.value.starts_with('-') || self.value.starts_with('+') {
+      format!("{}{}", self.label, self.value)
+    } else {
+      format!("{}+{}", self.label, self.value)
+    }
+  }
+}
+
+impl GerritEvent {
+  /// Returns the account that triggered the event.
+  fn actor(&self) -> Option<&User> {
+    let actor = match self.kind.as_str() {
+      "patchset-created" => &self.uploader,
+      "comment-added" => &self.author,
+      "change-merged" => &self.submitter,
+      "reviewer-added" => &self.adder,
+      "vote-deleted" => &self.remover,
+      _ => &None,
+    };
+
+    actor
+      .as_ref()
+      .or(self.uploader.as_ref())
+      .or(self.author.as_ref())
+      .or(self.submitter.as_ref())
+      .or(self.adder.as_ref())
+      .or(self.remover.as_ref())
+  }
+
+  fn vars(&self, endpoint: &str) -> HashMap<String, String> {
+    let actor = self.actor().cloned().unwrap_or_default();
+    let reviewer = self.reviewer.clone().unwrap_or_default();
+    // Comments repeat all current votes, changed ones come with the old value
+    let changed = self.approvals.iter().any(|a| a.old_value.is_some());
+    let approvals = self
+      .approvals
+      .iter()
+      .filter(|a| !changed || a.old_value.is_some())
+      .map(EventApproval::summary)
+      .collect::<Vec<_>>();
+
+    HashMap::from([
+      ("type".to_string(), self.kind.clone()),
+      ("url".to_string(), endpoint.to_string()),
+      ("change_url".to_string(), self.change.url.clone()),
+      ("project".to_string(), self.change.project.clone()),
+      ("branch".to_string(), self.change.branch.clone()),
+      ("topic".to_string(), self.change.topic.clone().unwrap_or_default()),
+      ("change_id".to_string(), self.change.id.clone()),
+      ("number".to_string(), self.change.number.to_string()),
+      ("subject".to_string(), self.change.subject.clone()),
+      ("owner_name".to_string(), self.change.owner.name.clone()),
+      ("owner_email".to_string(), self.change.owner.email.clone()),
+      ("owner_username".to_string(), self.change.owner.username.clone()),
+      (
+        "patchset".to_string(),
+        self
+          .patch_set
+          .as_ref()
+          .map(|p| p.number.to_string())
+          .unwrap_or_default(),
+      ),
+      (
+        "revision".to_string(),
+        self.patch_set.as_ref().map(|p| p.revision.clone()).unwrap_or_default(),
+      ),
+      (
+        "insertions".to_string(),
+        self
+          .patch_set
+          .as_ref()
+          .and_then(|p| p.size_insertions)
+          .unwrap_or_default()
+          .to_string(),
+      ),
+      (
+        "deletions".to_string(),
+        self
+          .patch_set
+          .as_ref()
+          .and_then(|p| p.size_deletions)
+          .map(|d| d.abs())
+          .unwrap_or_default()
+          .to_string(),
+      ),
+      ("actor_name".to_string(), actor.name),
+      ("actor_email".to_string(), actor.email),
+      ("actor_username".to_string(), actor.username),
+      ("reviewer_name".to_string(), reviewer.name),
+      ("reviewer_email".to_string(), reviewer.email),
+      ("reviewer_username".to_string(), reviewer.username),
+      ("approvals".to_string(), approvals.join(", ")),
+      ("comment".to_string(), self.comment.clone().unwrap_or_default()),
+    ])
+  }
+}
+
+impl GerritPlugin {
+  /// Turns a Gerrit event into a notification routed by the event type, events without a route are ignored.
+  pub(crate) fn process_event(task_id: &str, options: EventOptions) -> Result<Vec<PluginResult>, PluginError> {
+    // The type is checked first, events without a change such as `ref-updated` can't be parsed as `GerritEvent`
+    let kind = options.event.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+    let Some(route) = options.routes.get(kind) else {
+      return Ok(vec![]);
+    };
+
+    let event = serde_json::from_value::<GerritEvent>(options.event.clone())
+      .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit event: {}", err)))?;
+
+    let config = CONFIG
+      .lock()
+      .map_err(|e| PluginError::ConfigLock(e.to_string()))?
+      .clone()
+      .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
+
+    let vars = event.vars(&config.endpoint);
+    let message = strfmt(&route.template, &vars)
+      .map_err(|e| PluginError::Other(format!("Failed to format event template: {}", e)))?;
+
+    Ok(vec![GerritPlugin::stream_notification(
+      task_id,
+      options.notification.as_ref(),
+      &route.channel,
+      &route.topic,
+      message,
+    )?])
+  }
+}
@@ -1,64 +1,298 @@
 #![allow(deprecated)]
-use std::{collections::HashMap, sync::Mutex, time::Duration};
+mod assign;
+mod comments;
+mod conflicts;
+mod events;
+mod metrics;
+mod notification;
+mod query;
+mod related;
+mod review;
+mod stale;
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::Mutex,
+  time::Duration,
+};
 
 use base64::encode;
-use octabot_rust_sdk::{wit::export, ActionData, Error, Metadata, Plugin, PluginError, PluginResult};
+use chrono::{DateTime, Datelike, NaiveDate, TimeDelta, Utc, Weekday};
+use octabot_rust_sdk::{wit::export, Error, KeyValue, Metadata, Plugin, PluginError, PluginResult};
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use strfmt::strfmt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use strfmt::{strfmt, strfmt_map, FmtError, Formatter};
 use url::Url;
 use waki::{Client, Method, RequestBuilder};
 
+use crate::assign::AssignOptions;
+use crate::comments::CommentOptions;
+use crate::conflicts::ConflictOptions;
+use crate::events::EventOptions;
+use crate::metrics::MetricsOptions;
+use crate::notification::Notification;
+use crate::query::QueryFilter;
+use crate::review::ReviewOptions;
+use crate::stale::StaleOptions;
+
 const GERRIT_RESPONSE_PREFIX: &str = ")]}'";
 
+const DEFAULT_MAX_CHANGES: usize = 1000;
+
+const GERRIT_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+const DEFAULT_ATTENTION_TEMPLATE: &str = "The following changes are awaiting your action:\n";
+
+const DEFAULT_GROUP_TEMPLATE: &str = "**{group}**\n";
+
+// Tags starting with `autogenerated:` are hidden in the web UI by default, the warning is meant to be seen
+const STALE_WARNING_TAG: &str = "octabot:stale";
+
 static CONFIG: Lazy<Mutex<Option<Config>>> = Lazy::new(|| Mutex::new(None));
 
+mod gerrit_timestamp {
+  use chrono::{DateTime, NaiveDateTime, Utc};
+  use serde::{Deserialize, Deserializer, Serializer};
+
+  use super::GERRIT_TIMESTAMP_FORMAT;
+
+  pub fn serialize<S: Serializer>(value: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.format(GERRIT_TIMESTAMP_FORMAT).to_string())
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    NaiveDateTime::parse_from_str(&value, GERRIT_TIMESTAMP_FORMAT)
+      .map(|t| t.and_utc())
+      .map_err(serde::de::Error::custom)
+  }
+
+  pub mod option {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+      match value {
+        Some(value) => super::serialize(value, serializer),
+        None => serializer.serialize_none(),
+      }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+      #[derive(Deserialize)]
+      struct Timestamp(#[serde(with = "super")] DateTime<Utc>);
+
+      Ok(Option::<Timestamp>::deserialize(deserializer)?.map(|t| t.0))
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum AuthMode {
+  Anonymous,
+  Basic,
+  Bearer,
+  Cookie,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct Config {
   pub endpoint: String,
   pub timeout: Option<u64>,
-  pub login: String,
-  pub password: String,
+  pub auth: Option<AuthMode>,
+  pub login: Option<String>,
+  pub password: Option<String>,
+  pub token: Option<String>,
+  pub cookie: Option<String>,
+  pub xsrf_token: Option<String>,
+  #[serde(default)]
+  pub holidays: Vec<NaiveDate>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum TimestampField {
+  Created,
+  #[default]
+  Updated,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum AttentionSetNotify {
+  #[default]
+  Off,
+  WithDigest,
+  Only,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+enum GroupBy {
+  Owner,
+  Branch,
+  Project,
+  Topic,
+  Label(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct LeftSummary {
+  template: String,
+  review_template: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AgeBucket {
+  max_days: Option<u32>,
+  template: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProjectFilter {
+  project: Option<String>,
+  #[serde(default)]
+  projects: Vec<String>,
+  project_prefix: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct QueryOptions {
-  query: String,
+  query: Option<String>,
+  #[serde(default)]
+  filter: QueryFilter,
   channel: String,
   topic: String,
-  project: String,
+  #[serde(flatten)]
+  project_filter: ProjectFilter,
   template: String,
   review_template: String,
+  max_changes: Option<usize>,
+  sort_by: Option<TimestampField>,
+  #[serde(default)]
+  sort_descending: bool,
+  #[serde(default)]
+  age_from: TimestampField,
+  min_business_days: Option<u32>,
+  max_business_days: Option<u32>,
+  #[serde(default)]
+  age_buckets: Vec<AgeBucket>,
+  group_by: Option<GroupBy>,
+  group_template: Option<String>,
+  #[serde(default)]
+  only_new: bool,
+  left_summary: Option<LeftSummary>,
+  related_template: Option<String>,
+  #[serde(default)]
+  attention_set_notify: AttentionSetNotify,
+  attention_template: Option<String>,
+  #[serde(default)]
+  submit_requirements: bool,
+  notification: Option<Notification>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum Operation {
+  #[default]
+  Query,
+  Event,
+  Review,
+  AssignReviewers,
+  Metrics,
+  Stale,
+  Conflicts,
+  Comments,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Params {
   task_id: String,
-  options: QueryOptions,
+  #[serde(default)]
+  operation: Operation,
+  options: serde_json::Value,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
 struct User {
   name: String,
   email: String,
   username: String,
 }
 
+impl User {
+  /// Checks whether the account is referred to by the username or email.
+  fn is(&self, account: &str) -> bool {
+    (!self.username.is_empty() && self.username == account) || (!self.email.is_empty() && self.email == account)
+  }
+
+  /// Returns the email used for direct notifications, accounts without a visible email can't be notified.
+  fn notification_email(&self) -> Option<&str> {
+    (!self.email.is_empty()).then_some(self.email.as_str())
+  }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct AttentionSetEntry {
+  account: User,
+  reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Vote {
+  #[serde(flatten)]
+  account: User,
+  value: Option<i32>,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct Label {
   rejected: Option<User>,
   approved: Option<User>,
   disliked: Option<User>,
   recommended: Option<User>,
+  #[serde(default)]
+  all: Vec<Vote>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-struct Labels {
-  #[serde(alias = "Verified")]
-  verified: Label,
-  #[serde(alias = "Code-Review")]
-  code_review: Label,
+struct SubmitRequirementExpression {
+  #[serde(default)]
+  failing_atoms: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct SubmitRequirement {
+  name: String,
+  status: String,
+  submittability_expression_result: Option<SubmitRequirementExpression>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct CommitParent {
+  commit: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Commit {
+  #[serde(default)]
+  parents: Vec<CommitParent>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Revision {
+  commit: Option<Commit>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ChangeMessage {
+  author: Option<User>,
+  #[serde(with = "gerrit_timestamp")]
+  date: DateTime<Utc>,
+  tag: Option<String>,
+  _revision_number: Option<i32>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -69,137 +303,789 @@ struct Review {
   change_id: String,
   subject: String,
   status: String,
-  created: String,
-  updated: String,
+  topic: Option<String>,
+  #[serde(with = "gerrit_timestamp")]
+  created: DateTime<Utc>,
+  #[serde(with = "gerrit_timestamp")]
+  updated: DateTime<Utc>,
+  #[serde(default, with = "gerrit_timestamp::option")]
+  submitted: Option<DateTime<Utc>>,
   submit_type: String,
   insertions: i32,
   deletions: i32,
   unresolved_comment_count: i32,
   owner: User,
-  labels: Labels,
+  #[serde(default)]
+  labels: BTreeMap<String, Label>,
+  #[serde(default)]
+  reviewers: HashMap<String, Vec<User>>,
+  #[serde(default)]
+  attention_set: HashMap<String, AttentionSetEntry>,
+  #[serde(default)]
+  messages: Vec<ChangeMessage>,
+  #[serde(default)]
+  hashtags: Vec<String>,
+  mergeable: Option<bool>,
+  submittable: Option<bool>,
+  #[serde(default)]
+  submit_requirements: Vec<SubmitRequirement>,
+  current_revision: Option<String>,
+  #[serde(default)]
+  revisions: HashMap<String, Revision>,
   _number: i32,
+  #[serde(default)]
+  _more_changes: bool,
+}
+
+impl TryFrom<String> for GroupBy {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    match value.as_str() {
+      "owner" => Ok(GroupBy::Owner),
+      "branch" => Ok(GroupBy::Branch),
+      "project" => Ok(GroupBy::Project),
+      "topic" => Ok(GroupBy::Topic),
+      _ => match value.strip_prefix("label:") {
+        Some(label) => Ok(GroupBy::Label(label.to_string())),
+        None => Err(format!("unknown group_by value '{}'", value)),
+      },
+    }
+  }
+}
+
+impl From<GroupBy> for String {
+  fn from(value: GroupBy) -> Self {
+    match value {
+      GroupBy::Owner => "owner".to_string(),
+      GroupBy::Branch => "branch".to_string(),
+      GroupBy::Project => "project".to_string(),
+      GroupBy::Topic => "topic".to_string(),
+      GroupBy::Label(label) => format!("label:{}", label),
+    }
+  }
+}
+
+impl Label {
+  /// Returns the strongest vote on the label with the voter.
+  fn strongest_vote(&self) -> Option<(&'static str, &User)> {
+    let votes = [
+      ("rejected", &self.rejected),
+      ("approved", &self.approved),
+      ("disliked", &self.disliked),
+      ("recommended", &self.recommended),
+    ];
+
+    votes
+      .into_iter()
+      .find_map(|(vote, user)| user.as_ref().map(|u| (vote, u)))
+  }
+
+  /// Summarizes the strongest vote on the label, e.g. "rejected by John Doe".
+  fn summary(&self) -> String {
+    self
+      .strongest_vote()
+      .map(|(vote, user)| format!("{} by {}", vote, user.name))
+      .unwrap_or_default()
+  }
+
+  fn state(&self) -> &'static str {
+    self.strongest_vote().map(|(vote, _)| vote).unwrap_or("none")
+  }
+
+  /// Lists non-zero votes, e.g. "+2 John Doe, -1 Jane Doe".
+  fn votes(&self) -> String {
+    self
+      .all
+      .iter()
+      .filter_map(|vote| match vote.value {
+        Some(value) if value > 0 => Some(format!("+{} {}", value, vote.account.name)),
+        Some(value) if value < 0 => Some(format!("{} {}", value, vote.account.name)),
+        _ => None,
+      })
+      .collect::<Vec<_>>()
+      .join(", ")
+  }
+}
+
+impl SubmitRequirement {
+  fn is_blocking(&self) -> bool {
+    matches!(self.status.as_str(), "UNSATISFIED" | "ERROR")
+  }
+
+  /// Describes the requirement with its failing conditions, e.g. "Code-Review (label:Code-Review=MAX)".
+  fn summary(&self) -> String {
+    match self.submittability_expression_result.as_ref() {
+      Some(result) if !result.failing_atoms.is_empty() => {
+        format!("{} ({})", self.name, result.failing_atoms.join(", "))
+      },
+      _ => self.name.clone(),
+    }
+  }
+}
+
+impl Review {
+  /// Lists unsatisfied submit requirements of the change.
+  fn blockers(&self) -> String {
+    self
+      .submit_requirements
+      .iter()
+      .filter(|r| r.is_blocking())
+      .map(SubmitRequirement::summary)
+      .collect::<Vec<_>>()
+      .join(", ")
+  }
+
+  fn group(&self, group_by: &GroupBy) -> String {
+    match group_by {
+      GroupBy::Owner => self.owner.name.clone(),
+      GroupBy::Branch => self.branch.clone(),
+      GroupBy::Project => self.project.clone(),
+      GroupBy::Topic => self.topic.clone().unwrap_or_else(|| "No topic".to_string()),
+      GroupBy::Label(label) => format!("{}: {}", label, self.labels.get(label).map_or("none", Label::state)),
+    }
+  }
+
+  fn timestamp(&self, field: TimestampField) -> DateTime<Utc> {
+    match field {
+      TimestampField::Created => self.created,
+      TimestampField::Updated => self.updated,
+    }
+  }
+}
+
+impl Config {
+  /// Returns the configured auth mode, Basic auth is used when only `login` is set for compatibility.
+  fn auth_mode(&self) -> AuthMode {
+    match self.auth {
+      Some(mode) => mode,
+      None if self.login.is_some() => AuthMode::Basic,
+      None => AuthMode::Anonymous,
+    }
+  }
+
+  fn validate(&self) -> Result<(), PluginError> {
+    let missing = match self.auth_mode() {
+      AuthMode::Anonymous => None,
+      AuthMode::Basic if self.login.is_none() || self.password.is_none() => Some("login and password"),
+      AuthMode::Bearer if self.token.is_none() => Some("token"),
+      AuthMode::Cookie if self.cookie.is_none() => Some("cookie"),
+      _ => None,
+    };
+
+    match missing {
+      Some(fields) => Err(PluginError::ParseBotConfig(format!(
+        "{} auth requires {} to be set",
+        String::from(self.auth_mode()),
+        fields
+      ))),
+      None => Ok(()),
+    }
+  }
+}
+
+impl From<AuthMode> for String {
+  fn from(value: AuthMode) -> Self {
+    match value {
+      AuthMode::Anonymous => "anonymous".to_string(),
+      AuthMode::Basic => "basic".to_string(),
+      AuthMode::Bearer => "bearer".to_string(),
+      AuthMode::Cookie => "cookie".to_string(),
+    }
+  }
 }
 
 struct GerritPlugin;
 
 impl GerritPlugin {
-  fn request(path: &str) -> Result<RequestBuilder, PluginError> {
+  /// Builds a request to the REST API, `path` is given without the `/a/` prefix which is added for Basic and bearer
+  /// auth.
+  fn request(method: Method, path: &str) -> Result<RequestBuilder, PluginError> {
     let config = CONFIG
       .lock()
       .map_err(|e| PluginError::ConfigLock(e.to_string()))?
       .clone()
       .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
 
+    let mode = config.auth_mode();
+    let path = match mode {
+      AuthMode::Basic | AuthMode::Bearer => format!("a/{}", path),
+      AuthMode::Anonymous | AuthMode::Cookie => path.to_string(),
+    };
     let url = Url::parse(&format!("{}/{}", config.endpoint, path)).map_err(|e| PluginError::Other(e.to_string()))?;
 
-    let credentials = format!("{}:{}", config.login, config.password);
-    let authorization = encode(credentials);
+    let mut headers: Vec<(&'static str, String)> = vec![("Content-Type", "application/json".to_string())];
+    match mode {
+      AuthMode::Anonymous => {},
+      AuthMode::Basic => {
+        let credentials = format!(
+          "{}:{}",
+          config.login.unwrap_or_default(),
+          config.password.unwrap_or_default()
+        );
+        headers.push(("Authorization", format!("Basic {}", encode(credentials))));
+      },
+      AuthMode::Bearer => {
+        let token = config.token.unwrap_or_default();
+        headers.push(("Authorization", format!("Bearer {}", token)));
+      },
+      AuthMode::Cookie => {
+        headers.push(("Cookie", config.cookie.unwrap_or_default()));
+        // Gerrit checks the XSRF token on modifying requests made with the session cookie
+        if let Some(xsrf_token) = config.xsrf_token {
+          headers.push(("X-Gerrit-Auth", xsrf_token));
+        }
+      },
+    }
 
     let client = Client::new()
-      .request(Method::Get, url.as_str())
+      .request(method, url.as_str())
       .connect_timeout(Duration::from_secs(config.timeout.unwrap_or(60)))
-      .headers([
-        ("Content-Type", "application/json"),
-        ("Authorization", &format!("Basic {}", authorization.as_str())),
-      ]);
+      .headers(headers.iter().map(|(name, value)| (*name, value.as_str())));
 
     Ok(client)
   }
 
-  fn format_review_message(
-    review: &Review,
-    template: &str,
-    config: &Config,
-    params: &QueryOptions,
-  ) -> Result<String, PluginError> {
-    let vars = HashMap::from([
-      ("subject".to_string(), review.subject.clone()),
-      ("insertions".to_string(), review.insertions.to_string()),
-      ("deletions".to_string(), review.deletions.to_string()),
-      ("url".to_string(), config.endpoint.clone()),
-      ("number".to_string(), review._number.to_string()),
-      ("project".to_string(), params.project.to_string()),
-    ]);
+  fn unauthorized() -> PluginError {
+    let mode = CONFIG
+      .lock()
+      .ok()
+      .and_then(|config| config.as_ref().map(Config::auth_mode))
+      .unwrap_or(AuthMode::Anonymous);
 
-    strfmt(template, &vars).map_err(|e| PluginError::Other(format!("Failed to format review message template: {}", e)))
+    PluginError::Other(format!(
+      "Gerrit rejected the request as unauthorized, check the credentials of the {} auth",
+      String::from(mode)
+    ))
   }
-}
-
-impl Plugin for GerritPlugin {
-  fn process(payload: String) -> Result<Vec<PluginResult>, Error> {
-    let mut actions = vec![];
-    let params = serde_json::from_str::<Params>(&payload)
-      .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit query params: {}", err)))?;
-
-    let query = format!("{} project:{}", params.options.query, params.options.project);
-    let query = [
-      ("q", &query),
-      ("o", &"DETAILED_ACCOUNTS".to_string()),
-      ("o", &"LABELS".to_string()),
-    ];
-
-    let client = GerritPlugin::request("a/changes")?.query(&query);
 
+  fn send<T: DeserializeOwned>(client: RequestBuilder) -> Result<T, PluginError> {
     let resp = match client.send() {
       Ok(resp) => match resp.status_code() {
         200 => match String::from_utf8(resp.body().unwrap()) {
           Ok(resp) => resp,
-          Err(e) => return Err(PluginError::ParseResponse(e.to_string()).into()),
+          Err(e) => return Err(PluginError::ParseResponse(e.to_string())),
         },
-        code => return Err(PluginError::SendHttpRequest(format!("HTTP/{}", code)).into()),
+        401 => return Err(GerritPlugin::unauthorized()),
+        code => return Err(PluginError::SendHttpRequest(format!("HTTP/{}", code))),
       },
-      Err(e) => return Err(PluginError::SendHttpRequest(e.to_string()).into()),
+      Err(e) => return Err(PluginError::SendHttpRequest(e.to_string())),
     };
 
-    if resp == "Unauthorized" {
-      return Err(PluginError::Other("Authorization error".into()))?;
+    if resp.trim() == "Unauthorized" {
+      return Err(GerritPlugin::unauthorized());
     }
 
     let data = resp
       .strip_prefix(GERRIT_RESPONSE_PREFIX)
       .ok_or_else(|| PluginError::ParseResponse("Missing gerrit prefix in response".into()))?;
 
-    let reviews: Vec<Review> = serde_json::from_str(data)
-      .map_err(|e| PluginError::ParseResponse(format!("Failed to parse reviews from response: {}", e)))?;
+    serde_json::from_str(data).map_err(|e| PluginError::ParseResponse(format!("Failed to parse response: {}", e)))
+  }
+
+  fn query_changes(query: &str, max_changes: usize) -> Result<Vec<Review>, PluginError> {
+    GerritPlugin::query_changes_with(query, max_changes, &[])
+  }
 
-    if !reviews.is_empty() {
-      let vars = HashMap::from([("project".to_string(), params.options.project.to_string())]);
-      let mut message = strfmt(&params.options.template, &vars)
-        .map_err(|e| PluginError::Other(format!("Failed to format message template: {}", e)))?;
+  /// Runs a change query following `_more_changes` until all results are fetched or `max_changes` is reached,
+  /// `options` are requested in addition to detailed accounts and labels.
+  fn query_changes_with(query: &str, max_changes: usize, options: &[&str]) -> Result<Vec<Review>, PluginError> {
+    let mut reviews: Vec<Review> = vec![];
 
-      let config = CONFIG
-        .lock()
-        .map_err(|e| PluginError::ConfigLock(e.to_string()))?
-        .clone()
-        .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
+    while reviews.len() < max_changes {
+      let mut query = vec![
+        ("q", query.to_string()),
+        ("o", "DETAILED_ACCOUNTS".to_string()),
+        ("o", "DETAILED_LABELS".to_string()),
+        ("n", (max_changes - reviews.len()).to_string()),
+        ("S", reviews.len().to_string()),
+      ];
+      query.extend(options.iter().map(|o| ("o", o.to_string())));
 
-      for review in reviews {
-        let review_message =
-          GerritPlugin::format_review_message(&review, &params.options.review_template, &config, &params.options)?;
-        message.push_str(&review_message);
+      let client = GerritPlugin::request(Method::Get, "changes")?.query(&query);
+
+      let page: Vec<Review> = GerritPlugin::send(client)?;
+      let more_changes = page.last().is_some_and(|r| r._more_changes);
+
+      reviews.extend(page);
+      if !more_changes {
+        break;
       }
+    }
+
+    reviews.truncate(max_changes);
+
+    Ok(reviews)
+  }
+
+  /// Builds the full query with the project restriction, regex prefixes are passed to `project:` as is.
+  fn build_query(query: &str, filter: &ProjectFilter) -> Result<String, PluginError> {
+    let restrictions = [
+      filter.project.is_some(),
+      !filter.projects.is_empty(),
+      filter.project_prefix.is_some(),
+    ];
+    if restrictions.iter().filter(|r| **r).count() > 1 {
+      return Err(PluginError::Other(
+        "Only one of project, projects and project_prefix can be set".to_string(),
+      ));
+    }
+
+    let restriction = if let Some(project) = &filter.project {
+      format!("project:{}", project)
+    } else if !filter.projects.is_empty() {
+      let projects = filter
+        .projects
+        .iter()
+        .map(|p| format!("project:{}", p))
+        .collect::<Vec<_>>();
+      format!("({})", projects.join(" OR "))
+    } else if let Some(prefix) = &filter.project_prefix {
+      if prefix.starts_with('^') {
+        format!("project:{}", prefix)
+      } else {
+        format!("projects:{}", prefix)
+      }
+    } else {
+      return Ok(query.to_string());
+    };
+
+    // Implicit AND binds tighter than OR, so the query is parenthesized to restrict all of its alternatives
+    Ok(format!("({}) {}", query, restriction))
+  }
+
+  /// Counts business days passed since the timestamp, weekends and configured holidays are skipped.
+  fn business_days(since: DateTime<Utc>, now: DateTime<Utc>, holidays: &[NaiveDate]) -> u32 {
+    since
+      .date_naive()
+      .iter_days()
+      .skip(1)
+      .take_while(|day| *day <= now.date_naive())
+      .filter(|day| !matches!(day.weekday(), Weekday::Sat | Weekday::Sun) && !holidays.contains(day))
+      .count() as u32
+  }
+
+  fn format_age(age: TimeDelta) -> String {
+    if age.num_days() > 0 {
+      format!("{}d {}h", age.num_days(), age.num_hours() % 24)
+    } else if age.num_hours() > 0 {
+      format!("{}h {}m", age.num_hours(), age.num_minutes() % 60)
+    } else {
+      format!("{}m", age.num_minutes().max(0))
+    }
+  }
 
-      let action = ActionData {
-        name: "zulip".to_string(), // Name of notificationm zulip action
-        payload: json!({
-          "task_id": params.task_id,
-          "options": {
-            "channel": params.options.channel,
-            "topic": params.options.topic,
-            "message": message
+  /// Formats reviews grouped per project, or by `group_by` under a single header, optionally split into age buckets
+  /// inside each group.
+  fn format_digest(
+    reviews: &[Review],
+    options: &QueryOptions,
+    config: &Config,
+    now: DateTime<Utc>,
+  ) -> Result<String, PluginError> {
+    let format_header = |template: &str, vars: HashMap<String, String>| {
+      strfmt(template, &vars).map_err(|e| PluginError::Other(format!("Failed to format message template: {}", e)))
+    };
+
+    let mut message = String::new();
+    let mut sections: Vec<(String, Vec<&Review>)> = vec![];
+    match &options.group_by {
+      None => {
+        let mut projects: BTreeMap<&str, Vec<&Review>> = BTreeMap::new();
+        for review in reviews {
+          projects.entry(&review.project).or_default().push(review);
+        }
+
+        for (project, reviews) in projects {
+          let vars = HashMap::from([("project".to_string(), project.to_string())]);
+          sections.push((format_header(&options.template, vars)?, reviews));
+        }
+      },
+      Some(group_by) => {
+        let mut groups: BTreeMap<String, Vec<&Review>> = BTreeMap::new();
+        let mut projects: Vec<&str> = vec![];
+        for review in reviews {
+          groups.entry(review.group(group_by)).or_default().push(review);
+          if !projects.contains(&review.project.as_str()) {
+            projects.push(&review.project);
           }
-        })
-        .to_string(),
+        }
+
+        let vars = HashMap::from([("project".to_string(), projects.join(", "))]);
+        message.push_str(&format_header(&options.template, vars)?);
+
+        let group_template = options.group_template.as_deref().unwrap_or(DEFAULT_GROUP_TEMPLATE);
+        for (group, reviews) in groups {
+          let vars = HashMap::from([
+            ("group".to_string(), group),
+            ("count".to_string(), reviews.len().to_string()),
+          ]);
+          sections.push((format_header(group_template, vars)?, reviews));
+        }
+      },
+    }
+
+    for (header, reviews) in sections {
+      message.push_str(&header);
+      message.push_str(&GerritPlugin::format_reviews(&reviews, options, config, now)?);
+    }
+
+    Ok(message)
+  }
+
+  fn format_reviews(
+    reviews: &[&Review],
+    options: &QueryOptions,
+    config: &Config,
+    now: DateTime<Utc>,
+  ) -> Result<String, PluginError> {
+    if options.age_buckets.is_empty() {
+      return GerritPlugin::format_entries(reviews, options, config);
+    }
+
+    let mut message = String::new();
+    // Reviews go to the first bucket they fit in, a bucket without `max_days` takes the rest
+    let mut buckets: Vec<Vec<&Review>> = options.age_buckets.iter().map(|_| vec![]).collect();
+    for review in reviews {
+      let days = GerritPlugin::business_days(review.timestamp(options.age_from), now, &config.holidays);
+      if let Some(index) = options
+        .age_buckets
+        .iter()
+        .position(|bucket| bucket.max_days.is_none_or(|max| days <= max))
+      {
+        buckets[index].push(review);
+      }
+    }
+
+    for (bucket, reviews) in options.age_buckets.iter().zip(buckets) {
+      if reviews.is_empty() {
+        continue;
+      }
+
+      message.push_str(&bucket.template);
+      message.push_str(&GerritPlugin::format_entries(&reviews, options, config)?);
+    }
+
+    Ok(message)
+  }
+
+  /// Builds one direct message per account listing the changes where the account is in the attention set.
+  fn attention_set_notifications(
+    task_id: &str,
+    reviews: &[Review],
+    options: &QueryOptions,
+    config: &Config,
+  ) -> Result<Vec<PluginResult>, PluginError> {
+    let mut accounts: BTreeMap<&str, Vec<&Review>> = BTreeMap::new();
+    for review in reviews {
+      for entry in review.attention_set.values() {
+        if let Some(email) = entry.account.notification_email() {
+          accounts.entry(email).or_default().push(review);
+        }
+      }
+    }
+
+    let template = options
+      .attention_template
+      .as_deref()
+      .unwrap_or(DEFAULT_ATTENTION_TEMPLATE);
+    let mut notifications = vec![];
+    for (email, reviews) in accounts {
+      let mut message = template.to_string();
+      for review in reviews {
+        message.push_str(&GerritPlugin::format_review_message(
+          review,
+          &options.review_template,
+          config,
+        )?);
+      }
+
+      notifications.push(GerritPlugin::direct_notification(
+        task_id,
+        options.notification.as_ref(),
+        email,
+        message,
+      )?);
+    }
+
+    Ok(notifications)
+  }
+
+  fn state_key(task_id: &str, name: &str) -> String {
+    format!("gerrit:{}:{}", task_id, name)
+  }
+
+  /// Loads the state saved by a task under the name, missing state is the default value.
+  fn load_state<T: DeserializeOwned + Default>(keyvalue: &KeyValue, task_id: &str, name: &str) -> Result<T, Error> {
+    match keyvalue.get(&GerritPlugin::state_key(task_id, name))? {
+      Some(data) => Ok(
+        serde_json::from_slice(&data)
+          .map_err(|e| PluginError::Other(format!("Failed to parse {} state: {}", name, e)))?,
+      ),
+      None => Ok(T::default()),
+    }
+  }
+
+  fn save_state<T: Serialize + ?Sized>(keyvalue: &KeyValue, task_id: &str, name: &str, state: &T) -> Result<(), Error> {
+    let data = serde_json::to_vec(state)
+      .map_err(|e| PluginError::Other(format!("Failed to serialize {} state: {}", name, e)))?;
+    keyvalue.set(&GerritPlugin::state_key(task_id, name), &data)?;
+
+    Ok(())
+  }
+
+  /// Formats changes that dropped out of the query result since the last run with the reason they left.
+  fn format_left_summary(numbers: &[i32], summary: &LeftSummary, config: &Config) -> Result<String, PluginError> {
+    let query = numbers
+      .iter()
+      .map(|n| format!("change:{}", n))
+      .collect::<Vec<_>>()
+      .join(" OR ");
+    let reviews = GerritPlugin::query_changes(&query, numbers.len())?;
+
+    let mut message = summary.template.clone();
+    for review in reviews {
+      let reason = match review.status.as_str() {
+        "MERGED" => "merged",
+        "ABANDONED" => "abandoned",
+        _ => "reviewed",
       };
 
-      actions.push(PluginResult::Action(action));
+      let mut vars = GerritPlugin::review_vars(&review, config);
+      vars.insert("reason".to_string(), reason.to_string());
+      let line = GerritPlugin::format_review_vars(&summary.review_template, &vars)
+        .map_err(|e| PluginError::Other(format!("Failed to format left summary template: {}", e)))?;
+      message.push_str(&line);
+    }
+
+    Ok(message)
+  }
+
+  fn review_vars(review: &Review, config: &Config) -> HashMap<String, String> {
+    let now = Utc::now();
+    let age = now - review.created;
+    let idle = now - review.updated;
+
+    let mut vars = HashMap::from([
+      ("subject".to_string(), review.subject.clone()),
+      ("insertions".to_string(), review.insertions.to_string()),
+      ("deletions".to_string(), review.deletions.to_string()),
+      ("url".to_string(), config.endpoint.clone()),
+      ("number".to_string(), review._number.to_string()),
+      ("project".to_string(), review.project.clone()),
+      ("id".to_string(), review.id.clone()),
+      ("change_id".to_string(), review.change_id.clone()),
+      ("branch".to_string(), review.branch.clone()),
+      ("topic".to_string(), review.topic.clone().unwrap_or_default()),
+      ("status".to_string(), review.status.clone()),
+      (
+        "created".to_string(),
+        review.created.format("%Y-%m-%d %H:%M").to_string(),
+      ),
+      (
+        "updated".to_string(),
+        review.updated.format("%Y-%m-%d %H:%M").to_string(),
+      ),
+      ("age".to_string(), GerritPlugin::format_age(age)),
+      ("age_days".to_string(), age.num_days().to_string()),
+      ("idle".to_string(), GerritPlugin::format_age(idle)),
+      ("idle_days".to_string(), idle.num_days().to_string()),
+      (
+        "business_days".to_string(),
+        GerritPlugin::business_days(review.updated, now, &config.holidays).to_string(),
+      ),
+      ("submit_type".to_string(), review.submit_type.clone()),
+      (
+        "unresolved_comment_count".to_string(),
+        review.unresolved_comment_count.to_string(),
+      ),
+      ("owner_name".to_string(), review.owner.name.clone()),
+      ("owner_email".to_string(), review.owner.email.clone()),
+      ("owner_username".to_string(), review.owner.username.clone()),
+      (
+        "submittable".to_string(),
+        review.submittable.map(|s| s.to_string()).unwrap_or_default(),
+      ),
+      ("blockers".to_string(), review.blockers()),
+    ]);
+
+    for (name, label) in &review.labels {
+      let key = format!("label_{}", name.to_lowercase().replace('-', "_"));
+      vars.insert(format!("{}_state", key), label.state().to_string());
+      vars.insert(format!("{}_votes", key), label.votes());
+      vars.insert(key, label.summary());
+    }
+
+    vars
+  }
+
+  /// Formats a template with review variables, labels missing on the change are formatted as empty.
+  fn format_review_vars(template: &str, vars: &HashMap<String, String>) -> Result<String, FmtError> {
+    strfmt_map(template, |mut fmt: Formatter| match vars.get(fmt.key) {
+      Some(value) => fmt.str(value),
+      None if fmt.key.starts_with("label_") => fmt.str(""),
+      None => Err(FmtError::KeyError(fmt.key.to_string())),
+    })
+  }
+
+  fn format_review_message(review: &Review, template: &str, config: &Config) -> Result<String, PluginError> {
+    let vars = GerritPlugin::review_vars(review, config);
+
+    GerritPlugin::format_review_vars(template, &vars)
+      .map_err(|e| PluginError::Other(format!("Failed to format review message template: {}", e)))
+  }
+
+  fn process_query(task_id: &str, options: QueryOptions) -> Result<Vec<PluginResult>, Error> {
+    let mut actions = vec![];
+    let query = options
+      .filter
+      .combine(options.query.as_deref())
+      .map_err(|e| PluginError::ParseActionPaylod(format!("invalid gerrit query options: {}", e)))?;
+    let query = GerritPlugin::build_query(&query, &options.project_filter)?;
+    let max_changes = options.max_changes.unwrap_or(DEFAULT_MAX_CHANGES);
+
+    let config = CONFIG
+      .lock()
+      .map_err(|e| PluginError::ConfigLock(e.to_string()))?
+      .clone()
+      .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
+
+    let now = Utc::now();
+    let mut extra_options = vec![];
+    if options.submit_requirements {
+      extra_options.extend(["SUBMITTABLE", "SUBMIT_REQUIREMENTS"]);
+    }
+    // Parent commits tell which changes are stacked on each other
+    if options.related_template.is_some() {
+      extra_options.extend(["CURRENT_REVISION", "CURRENT_COMMIT"]);
+    }
+    let mut reviews = GerritPlugin::query_changes_with(&query, max_changes, &extra_options)?;
+    let matched = reviews.iter().map(|r| r._number).collect::<Vec<_>>();
+    reviews.retain(|review| {
+      let days = GerritPlugin::business_days(review.timestamp(options.age_from), now, &config.holidays);
+      options.min_business_days.is_none_or(|min| days >= min) && options.max_business_days.is_none_or(|max| days <= max)
+    });
+
+    if let Some(sort_by) = options.sort_by {
+      reviews.sort_by_key(|review| review.timestamp(sort_by));
+      if options.sort_descending {
+        reviews.reverse();
+      }
+    }
+
+    let mut left_summary = None;
+    if options.only_new {
+      let keyvalue = KeyValue::open()?;
+      let seen: Vec<i32> = GerritPlugin::load_state(&keyvalue, task_id, "seen")?;
+
+      // Changes only left out by the business day filter are still matched by Gerrit, so they stay seen. With
+      // `max_changes` reached a missing change may be over the limit, so missing changes stay seen too.
+      let truncated = matched.len() >= max_changes;
+      let (mut current, left): (Vec<i32>, Vec<i32>) = seen.iter().partition(|n| truncated || matched.contains(n));
+      current.extend(reviews.iter().map(|r| r._number).filter(|n| !seen.contains(n)));
+
+      if let Some(summary) = &options.left_summary {
+        if !left.is_empty() {
+          left_summary = Some(GerritPlugin::format_left_summary(&left, summary, &config)?);
+        }
+      }
+
+      reviews.retain(|r| !seen.contains(&r._number));
+      GerritPlugin::save_state(&keyvalue, task_id, "seen", &current)?;
+    }
+
+    if options.attention_set_notify != AttentionSetNotify::Only && (!reviews.is_empty() || left_summary.is_some()) {
+      let mut message = String::new();
+      if !reviews.is_empty() {
+        message.push_str(&GerritPlugin::format_digest(&reviews, &options, &config, now)?);
+      }
+      if let Some(left_summary) = left_summary {
+        message.push_str(&left_summary);
+      }
+
+      actions.push(GerritPlugin::stream_notification(
+        task_id,
+        options.notification.as_ref(),
+        &options.channel,
+        &options.topic,
+        message,
+      )?);
+    }
+
+    if !reviews.is_empty() && options.attention_set_notify != AttentionSetNotify::Off {
+      actions.extend(GerritPlugin::attention_set_notifications(
+        task_id, &reviews, &options, &config,
+      )?);
     }
 
     Ok(actions)
   }
+}
+
+impl Plugin for GerritPlugin {
+  fn process(payload: String) -> Result<Vec<PluginResult>, Error> {
+    let params = serde_json::from_str::<Params>(&payload)
+      .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit params: {}", err)))?;
+
+    match params.operation {
+      Operation::Query => {
+        let options = serde_json::from_value::<QueryOptions>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit query params: {}", err)))?;
+
+        GerritPlugin::process_query(&params.task_id, options)
+      },
+      Operation::Event => {
+        let options = serde_json::from_value::<EventOptions>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit event: {}", err)))?;
+
+        Ok(GerritPlugin::process_event(&params.task_id, options)?)
+      },
+      Operation::Review => {
+        let options = serde_json::from_value::<ReviewOptions>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit review: {}", err)))?;
+
+        GerritPlugin::post_review(&options)?;
+
+        Ok(vec![])
+      },
+      Operation::AssignReviewers => {
+        let options = serde_json::from_value::<AssignOptions>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit assign options: {}", err)))?;
+
+        GerritPlugin::assign_reviewers(&params.task_id, &options)?;
+
+        Ok(vec![])
+      },
+      Operation::Metrics => {
+        let options = serde_json::from_value::<MetricsOptions>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit metrics options: {}", err)))?;
+
+        Ok(GerritPlugin::process_metrics(&params.task_id, &options)?)
+      },
+      Operation::Stale => {
+        let options = serde_json::from_value::<StaleOptions>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit stale options: {}", err)))?;
+
+        GerritPlugin::process_stale(&params.task_id, &options)
+      },
+      Operation::Conflicts => {
+        let options = serde_json::from_value::<ConflictOptions>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit conflict options: {}", err)))?;
+
+        GerritPlugin::process_conflicts(&params.task_id, &options)
+      },
+      Operation::Comments => {
+        let options = serde_json::from_value::<CommentOptions>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse gerrit comment options: {}", err)))?;
+
+        Ok(GerritPlugin::process_comments(&params.task_id, &options)?)
+      },
+    }
+  }
 
   fn init(config: String) -> Result<(), Error> {
     let config = serde_json::from_str::<Config>(&config).map_err(|err| PluginError::ParseBotConfig(err.to_string()))?;
+    config.validate()?;
     let mut global_config = CONFIG.lock().map_err(|e| PluginError::ConfigLock(e.to_string()))?;
     *global_config = Some(config.clone());
 
@@ -217,3 +1103,42 @@ impl Plugin for GerritPlugin {
 }
 
 export!(GerritPlugin with_types_in octabot_rust_sdk::wit);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn at(date: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(&format!("{}T12:00:00Z", date))
+      .unwrap()
+      .with_timezone(&Utc)
+  }
+
+  #[test]
+  fn business_days_same_day() {
+    assert_eq!(GerritPlugin::business_days(at("2024-01-03"), at("2024-01-03"), &[]), 0);
+  }
+
+  #[test]
+  fn business_days_skip_weekends() {
+    // Friday to Monday
+    assert_eq!(GerritPlugin::business_days(at("2024-01-05"), at("2024-01-08"), &[]), 1);
+    // Saturday to Sunday
+    assert_eq!(GerritPlugin::business_days(at("2024-01-06"), at("2024-01-07"), &[]), 0);
+    // Monday to Monday
+    assert_eq!(GerritPlugin::business_days(at("2024-01-01"), at("2024-01-08"), &[]), 5);
+  }
+
+  #[test]
+  fn business_days_skip_holidays() {
+    let holidays = [NaiveDate::from_ymd_opt(2024, 1, 2).unwrap()];
+    assert_eq!(
+      GerritPlugin::business_days(at("2024-01-01"), at("2024-01-03"), &holidays),
+      1
+    );
+    assert_eq!(
+      GerritPlugin::business_days(at("2024-01-01"), at("2024-01-02"), &holidays),
+      0
+    );
+  }
+}
@@ -0,0 +1,178 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use octabot_rust_sdk::{PluginError, PluginResult};
+use serde::{Deserialize, Serialize};
+use strfmt::strfmt;
+
+use crate::{
+  notification::Notification, ChangeMessage, GerritPlugin, ProjectFilter, Review, User, DEFAULT_MAX_CHANGES,
+  STALE_WARNING_TAG,
+};
+
+const DEFAULT_METRICS_DAYS: u32 = 7;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MetricsOptions {
+  query: Option<String>,
+  channel: String,
+  topic: String,
+  #[serde(flatten)]
+  project_filter: ProjectFilter,
+  template: String,
+  days: Option<u32>,
+  max_changes: Option<usize>,
+  notification: Option<Notification>,
+}
+
+#[derive(Default)]
+struct ProjectMetrics {
+  changes: usize,
+  merged: usize,
+  first_review: Vec<TimeDelta>,
+  merge: Vec<TimeDelta>,
+  patchsets: Vec<i32>,
+  reviews: HashMap<String, usize>,
+}
+
+impl ChangeMessage {
+  /// Returns the author if the message is a review left by someone other than the owner, bot messages and stale
+  /// warnings are skipped.
+  fn reviewer(&self, owner: &User) -> Option<&User> {
+    let author = self.author.as_ref()?;
+    let automated = self
+      .tag
+      .as_ref()
+      .is_some_and(|tag| tag.starts_with("autogenerated:") || tag == STALE_WARNING_TAG);
+    if automated || author.username == owner.username {
+      return None;
+    }
+
+    Some(author)
+  }
+}
+
+impl ProjectMetrics {
+  fn add(&mut self, review: &Review, since: DateTime<Utc>) {
+    for message in review.messages.iter().filter(|m| m.date >= since) {
+      if let Some(author) = message.reviewer(&review.owner) {
+        let name = if author.name.is_empty() {
+          &author.username
+        } else {
+          &author.name
+        };
+        *self.reviews.entry(name.clone()).or_default() += 1;
+      }
+    }
+
+    if review.created < since {
+      return;
+    }
+
+    self.changes += 1;
+    if let Some(first) = review.messages.iter().find(|m| m.reviewer(&review.owner).is_some()) {
+      self.first_review.push(first.date - review.created);
+    }
+    if let Some(submitted) = review.submitted {
+      self.merged += 1;
+      self.merge.push(submitted - review.created);
+    }
+    if let Some(patchsets) = review.messages.iter().filter_map(|m| m._revision_number).max() {
+      self.patchsets.push(patchsets);
+    }
+  }
+
+  fn median(values: &mut [TimeDelta]) -> String {
+    if values.is_empty() {
+      return "-".to_string();
+    }
+
+    values.sort();
+    GerritPlugin::format_age(values[values.len() / 2])
+  }
+
+  /// Formats the metrics and the reviews per reviewer as markdown tables.
+  fn table(&mut self) -> String {
+    let patchsets = if self.patchsets.is_empty() {
+      "-".to_string()
+    } else {
+      format!(
+        "{:.1}",
+        self.patchsets.iter().sum::<i32>() as f64 / self.patchsets.len() as f64
+      )
+    };
+
+    let mut table = String::from(
+      "| Changes | Merged | Time to first review | Time to merge | Patchsets per change |\n|---|---|---|---|---|\n",
+    );
+    table.push_str(&format!(
+      "| {} | {} | {} | {} | {} |\n",
+      self.changes,
+      self.merged,
+      ProjectMetrics::median(&mut self.first_review),
+      ProjectMetrics::median(&mut self.merge),
+      patchsets
+    ));
+
+    if !self.reviews.is_empty() {
+      let mut reviewers = self.reviews.iter().collect::<Vec<_>>();
+      reviewers.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+      table.push_str("\n| Reviewer | Reviews |\n|---|---|\n");
+      for (reviewer, reviews) in reviewers {
+        table.push_str(&format!("| {} | {} |\n", reviewer, reviews));
+      }
+    }
+
+    table
+  }
+}
+
+impl GerritPlugin {
+  /// Reports review turnaround per project over the last `days` days: median time to first review and to merge,
+  /// patchsets per change and reviews per reviewer.
+  pub(crate) fn process_metrics(task_id: &str, options: &MetricsOptions) -> Result<Vec<PluginResult>, PluginError> {
+    let days = options.days.unwrap_or(DEFAULT_METRICS_DAYS);
+    let since = Utc::now() - TimeDelta::days(days as i64);
+
+    // `after:` matches the update time, changes created earlier only count towards reviews per reviewer
+    let query = match &options.query {
+      Some(query) => format!("({}) after:\"{}\"", query, since.format("%Y-%m-%d")),
+      None => format!("after:\"{}\"", since.format("%Y-%m-%d")),
+    };
+    let query = GerritPlugin::build_query(&query, &options.project_filter)?;
+    let max_changes = options.max_changes.unwrap_or(DEFAULT_MAX_CHANGES);
+    let reviews = GerritPlugin::query_changes_with(&query, max_changes, &["MESSAGES"])?;
+
+    let mut projects: BTreeMap<&str, ProjectMetrics> = BTreeMap::new();
+    for review in &reviews {
+      projects.entry(&review.project).or_default().add(review, since);
+    }
+
+    if projects.is_empty() {
+      return Ok(vec![]);
+    }
+
+    let mut message = String::new();
+    for (project, mut metrics) in projects {
+      let vars = HashMap::from([
+        ("project".to_string(), project.to_string()),
+        ("days".to_string(), days.to_string()),
+      ]);
+      let header = strfmt(&options.template, &vars)
+        .map_err(|e| PluginError::Other(format!("Failed to format metrics template: {}", e)))?;
+
+      message.push_str(&header);
+      message.push_str(&metrics.table());
+      message.push('\n');
+    }
+
+    Ok(vec![GerritPlugin::stream_notification(
+      task_id,
+      options.notification.as_ref(),
+      &options.channel,
+      &options.topic,
+      message.trim_end().to_string(),
+    )?])
+  }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use octabot_rust_sdk::{ActionData, PluginError, PluginResult};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use strfmt::strfmt;
+
+use crate::GerritPlugin;
+
+/// Action receiving notifications, string values of the payload are templates with the `task_id`, `channel`, `topic`,
+/// `user` and `message` variables.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Notification {
+  action: String,
+  payload: Value,
+  direct_payload: Option<Value>,
+}
+
+impl Notification {
+  fn format_payload(value: &Value, vars: &HashMap<String, String>) -> Result<Value, PluginError> {
+    let value = match value {
+      Value::String(template) => Value::String(
+        strfmt(template, vars)
+          .map_err(|e| PluginError::Other(format!("Failed to format notification payload: {}", e)))?,
+      ),
+      Value::Array(values) => Value::Array(
+        values
+          .iter()
+          .map(|v| Notification::format_payload(v, vars))
+          .collect::<Result<_, _>>()?,
+      ),
+      Value::Object(fields) => Value::Object(
+        fields
+          .iter()
+          .map(|(k, v)| Ok((k.clone(), Notification::format_payload(v, vars)?)))
+          .collect::<Result<_, PluginError>>()?,
+      ),
+      value => value.clone(),
+    };
+
+    Ok(value)
+  }
+
+  fn action(&self, payload: &Value, vars: HashMap<String, String>) -> Result<PluginResult, PluginError> {
+    Ok(PluginResult::Action(ActionData {
+      name: self.action.clone(),
+      payload: Notification::format_payload(payload, &vars)?.to_string(),
+    }))
+  }
+}
+
+impl GerritPlugin {
+  fn notification_vars(
+    task_id: &str,
+    channel: &str,
+    topic: &str,
+    user: &str,
+    message: String,
+  ) -> HashMap<String, String> {
+    HashMap::from([
+      ("task_id".to_string(), task_id.to_string()),
+      ("channel".to_string(), channel.to_string()),
+      ("topic".to_string(), topic.to_string()),
+      ("user".to_string(), user.to_string()),
+      ("message".to_string(), message),
+    ])
+  }
+
+  /// Builds a notification to a channel, the zulip action is used unless a target is configured.
+  pub(crate) fn stream_notification(
+    task_id: &str,
+    target: Option<&Notification>,
+    channel: &str,
+    topic: &str,
+    message: String,
+  ) -> Result<PluginResult, PluginError> {
+    if let Some(target) = target {
+      let vars = GerritPlugin::notification_vars(task_id, channel, topic, "", message);
+      return target.action(&target.payload, vars);
+    }
+
+    Ok(PluginResult::Action(ActionData {
+      name: "zulip".to_string(), // Name of notificationm zulip action
+      payload: json!({
+        "task_id": task_id,
+        "options": {
+          "channel": channel,
+          "topic": topic,
+          "message": message
+        }
+      })
+      .to_string(),
+    }))
+  }
+
+  /// Builds a personal notification, a configured target uses `direct_payload` falling back to `payload`.
+  pub(crate) fn direct_notification(
+    task_id: &str,
+    target: Option<&Notification>,
+    user: &str,
+    message: String,
+  ) -> Result<PluginResult, PluginError> {
+    if let Some(target) = target {
+      let vars = GerritPlugin::notification_vars(task_id, "", "", user, message);
+      return target.action(target.direct_payload.as_ref().unwrap_or(&target.payload), vars);
+    }
+
+    Ok(PluginResult::Action(ActionData {
+      name: "zulip".to_string(),
+      payload: json!({
+        "task_id": task_id,
+        "options": {
+          "user": user,
+          "message": message
+        }
+      })
+      .to_string(),
+    }))
+  }
+}
@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+
+const AGE_UNITS: [&str; 25] = [
+  "s", "sec", "second", "seconds", "m", "min", "minute", "minutes", "h", "hr", "hour", "hours", "d", "day", "days",
+  "w", "week", "weeks", "mon", "month", "months", "y", "yr", "year", "years",
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ChangeStatus {
+  Open,
+  Merged,
+  Abandoned,
+  Closed,
+}
+
+/// Operator value quoted in the query, values with double quotes can't be quoted and are rejected.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+struct QueryValue(String);
+
+/// Age in the Gerrit format, e.g. `2d` or `1week`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+struct Age(String);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+enum Comparison {
+  #[default]
+  #[serde(rename = "=")]
+  Eq,
+  #[serde(rename = ">=")]
+  Ge,
+  #[serde(rename = "<=")]
+  Le,
+  #[serde(rename = ">")]
+  Gt,
+  #[serde(rename = "<")]
+  Lt,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct LabelCondition {
+  #[serde(rename = "label")]
+  name: LabelName,
+  #[serde(default)]
+  op: Comparison,
+  value: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+struct LabelName(String);
+
+/// Structured query options compiled into a Gerrit query.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct QueryFilter {
+  status: Option<ChangeStatus>,
+  age: Option<Age>,
+  owner: Option<QueryValue>,
+  reviewer: Option<QueryValue>,
+  branch: Option<QueryValue>,
+  #[serde(default)]
+  labels: Vec<LabelCondition>,
+  #[serde(default)]
+  hashtags: Vec<QueryValue>,
+  #[serde(default)]
+  exclude_wip: bool,
+  #[serde(default)]
+  exclude_private: bool,
+}
+
+impl TryFrom<String> for QueryValue {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    if value.is_empty() {
+      return Err("query value can't be empty".to_string());
+    }
+    if value.contains('"') {
+      return Err(format!("query value '{}' can't contain double quotes", value));
+    }
+
+    Ok(QueryValue(value))
+  }
+}
+
+impl From<QueryValue> for String {
+  fn from(value: QueryValue) -> Self {
+    value.0
+  }
+}
+
+impl TryFrom<String> for Age {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    let unit = value.trim_start_matches(|c: char| c.is_ascii_digit());
+    if unit.len() == value.len() || !AGE_UNITS.contains(&unit) {
+      return Err(format!(
+        "invalid age '{}', expected a number with a unit, e.g. 2d",
+        value
+      ));
+    }
+
+    Ok(Age(value))
+  }
+}
+
+impl From<Age> for String {
+  fn from(value: Age) -> Self {
+    value.0
+  }
+}
+
+impl TryFrom<String> for LabelName {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+      return Err(format!("invalid label name '{}'", value));
+    }
+
+    Ok(LabelName(value))
+  }
+}
+
+impl From<LabelName> for String {
+  fn from(value: LabelName) -> Self {
+    value.0
+  }
+}
+
+impl Comparison {
+  fn operator(&self) -> &'static str {
+    match self {
+      Comparison::Eq => "=",
+      Comparison::Ge => ">=",
+      Comparison::Le => "<=",
+      Comparison::Gt => ">",
+      Comparison::Lt => "<",
+    }
+  }
+}
+
+impl QueryValue {
+  fn quoted(&self) -> String {
+    format!("\"{}\"", self.0)
+  }
+}
+
+impl QueryFilter {
+  /// Compiles the options into query terms joined with AND, e.g. `status:open owner:"jdoe" -is:wip`.
+  fn compile(&self) -> String {
+    let mut terms = vec![];
+    if let Some(status) = self.status {
+      let status = match status {
+        ChangeStatus::Open => "open",
+        ChangeStatus::Merged => "merged",
+        ChangeStatus::Abandoned => "abandoned",
+        ChangeStatus::Closed => "closed",
+      };
+      terms.push(format!("status:{}", status));
+    }
+    if let Some(age) = &self.age {
+      terms.push(format!("age:{}", age.0));
+    }
+    if let Some(owner) = &self.owner {
+      terms.push(format!("owner:{}", owner.quoted()));
+    }
+    if let Some(reviewer) = &self.reviewer {
+      terms.push(format!("reviewer:{}", reviewer.quoted()));
+    }
+    if let Some(branch) = &self.branch {
+      terms.push(format!("branch:{}", branch.quoted()));
+    }
+    for label in &self.labels {
+      terms.push(format!("label:{}{}{}", label.name.0, label.op.operator(), label.value));
+    }
+    for hashtag in &self.hashtags {
+      terms.push(format!("hashtag:{}", hashtag.quoted()));
+    }
+    if self.exclude_wip {
+      terms.push("-is:wip".to_string());
+    }
+    if self.exclude_private {
+      terms.push("-is:private".to_string());
+    }
+
+    terms.join(" ")
+  }
+
+  /// Combines the raw query with the structured options, the raw query is always parenthesized to keep its `OR`s
+  /// inside when more terms are appended.
+  pub(crate) fn combine(&self, raw: Option<&str>) -> Result<String, String> {
+    let compiled = self.compile();
+    match (raw.map(str::trim).filter(|q| !q.is_empty()), compiled.is_empty()) {
+      (Some(raw), true) => Ok(format!("({})", raw)),
+      (Some(raw), false) => Ok(format!("({}) {}", raw, compiled)),
+      (None, false) => Ok(compiled),
+      (None, true) => Err("either query or filter options must be set".to_string()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+  use crate::{GerritPlugin, ProjectFilter};
+
+  fn filter(value: serde_json::Value) -> Result<QueryFilter, serde_json::Error> {
+    serde_json::from_value(value)
+  }
+
+  #[test]
+  fn compile_quotes_values() {
+    let filter = filter(json!({
+      "status": "open",
+      "age": "2d",
+      "owner": "jdoe",
+      "branch": "release/1.0",
+      "labels": [{ "label": "Code-Review", "op": ">=", "value": 1 }, { "label": "Verified", "value": -1 }],
+      "hashtags": ["needs review"],
+      "exclude_wip": true,
+      "exclude_private": true
+    }))
+    .unwrap();
+
+    assert_eq!(
+      filter.compile(),
+      "status:open age:2d owner:\"jdoe\" branch:\"release/1.0\" label:Code-Review>=1 label:Verified=-1 \
+       hashtag:\"needs review\" -is:wip -is:private"
+    );
+  }
+
+  #[test]
+  fn combine_parenthesizes_raw_query() {
+    let filter = filter(json!({ "status": "open" })).unwrap();
+    assert_eq!(
+      filter.combine(Some("owner:a OR owner:b")).unwrap(),
+      "(owner:a OR owner:b) status:open"
+    );
+    assert_eq!(filter.combine(Some("  ")).unwrap(), "status:open");
+    assert_eq!(filter.combine(None).unwrap(), "status:open");
+
+    let empty = QueryFilter::default();
+    assert_eq!(empty.combine(Some("is:open")).unwrap(), "(is:open)");
+    assert!(empty.combine(None).is_err());
+  }
+
+  #[test]
+  fn project_restriction_applies_to_raw_or_query() {
+    let projects: ProjectFilter = serde_json::from_value(json!({ "projects": ["core", "web"] })).unwrap();
+    let query = QueryFilter::default().combine(Some("owner:a OR owner:b")).unwrap();
+    assert_eq!(
+      GerritPlugin::build_query(&query, &projects).unwrap(),
+      "((owner:a OR owner:b)) (project:core OR project:web)"
+    );
+  }
+
+  #[test]
+  fn age_requires_number_and_unit() {
+    assert!(Age::try_from("2d".to_string()).is_ok());
+    assert!(Age::try_from("10weeks".to_string()).is_ok());
+    assert!(Age::try_from("2".to_string()).is_err());
+    assert!(Age::try_from("d".to_string()).is_err());
+    assert!(Age::try_from("2 days".to_string()).is_err());
+    assert!(Age::try_from("2fortnights".to_string()).is_err());
+  }
+
+  #[test]
+  fn rejects_unquotable_values() {
+    assert!(filter(json!({ "owner": "" })).is_err());
+    assert!(filter(json!({ "owner": "j\"doe" })).is_err());
+    assert!(filter(json!({ "labels": [{ "label": "Code Review", "value": 1 }] })).is_err());
+  }
+}
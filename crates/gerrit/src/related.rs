@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+
+use octabot_rust_sdk::PluginError;
+use serde::Deserialize;
+use waki::Method;
+
+use crate::{Config, GerritPlugin, QueryOptions, Review};
+
+#[derive(Deserialize)]
+struct RelatedChange {
+  _change_number: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct RelatedChanges {
+  #[serde(default)]
+  changes: Vec<RelatedChange>,
+}
+
+impl Review {
+  fn parent_commits(&self) -> Vec<&str> {
+    self
+      .current_revision
+      .as_ref()
+      .and_then(|revision| self.revisions.get(revision))
+      .and_then(|revision| revision.commit.as_ref())
+      .map(|commit| commit.parents.iter().map(|p| p.commit.as_str()).collect())
+      .unwrap_or_default()
+  }
+
+  /// Checks whether the change is ready to submit, by `submittable` when requested or by all labels being approved.
+  fn is_ready(&self) -> bool {
+    match self.submittable {
+      Some(submittable) => submittable,
+      None => self.labels.values().all(|label| label.state() == "approved"),
+    }
+  }
+}
+
+impl GerritPlugin {
+  /// Returns change numbers of the relation chain of the change, from the base to the tip.
+  fn related_changes(review: &Review) -> Result<Vec<i32>, PluginError> {
+    let path = format!(
+      "changes/{}/revisions/current/related",
+      GerritPlugin::encode_id(&review.id)
+    );
+    let client = GerritPlugin::request(Method::Get, &path)?;
+    let related: RelatedChanges = GerritPlugin::send(client)?;
+
+    Ok(related.changes.iter().rev().filter_map(|c| c._change_number).collect())
+  }
+
+  /// Splits reviews into groups of changes sharing a topic or a relation chain, each ordered from the base.
+  fn related_groups<'a>(reviews: &[&'a Review]) -> Vec<Vec<&'a Review>> {
+    fn find(parents: &mut [usize], i: usize) -> usize {
+      let mut root = i;
+      while parents[root] != root {
+        root = parents[root];
+      }
+      parents[i] = root;
+      root
+    }
+
+    fn union(parents: &mut [usize], a: usize, b: usize) {
+      let (a, b) = (find(parents, a), find(parents, b));
+      parents[a.max(b)] = a.min(b);
+    }
+
+    let mut parents = (0..reviews.len()).collect::<Vec<_>>();
+
+    let indexes = reviews
+      .iter()
+      .enumerate()
+      .map(|(i, r)| (r._number, i))
+      .collect::<HashMap<_, _>>();
+
+    let mut topics: HashMap<&str, usize> = HashMap::new();
+    for (i, review) in reviews.iter().enumerate() {
+      if let Some(topic) = review.topic.as_deref().filter(|t| !t.is_empty()) {
+        match topics.get(topic) {
+          Some(j) => union(&mut parents, i, *j),
+          None => {
+            topics.insert(topic, i);
+          },
+        }
+      }
+    }
+
+    // Only changes stacked on another change of the results are looked up, and a chain is fetched once, the other
+    // changes of the chain found in the results already have their depth
+    let revisions = reviews
+      .iter()
+      .filter_map(|r| r.current_revision.as_deref())
+      .collect::<HashSet<_>>();
+    let mut depths: HashMap<i32, usize> = HashMap::new();
+    for (i, review) in reviews.iter().enumerate() {
+      let stacked = review.parent_commits().iter().any(|c| revisions.contains(c));
+      if !stacked || depths.contains_key(&review._number) {
+        continue;
+      }
+
+      // A failed lookup leaves the change unrelated instead of failing the whole digest
+      let related = match GerritPlugin::related_changes(review) {
+        Ok(related) => related,
+        Err(e) => {
+          println!("Failed to fetch related changes of {}: {}", review._number, e);
+          continue;
+        },
+      };
+      for (depth, number) in related.into_iter().enumerate() {
+        depths.insert(number, depth);
+        if let Some(j) = indexes.get(&number) {
+          union(&mut parents, i, *j);
+        }
+      }
+    }
+
+    let mut groups: Vec<(usize, Vec<&Review>)> = vec![];
+    for (i, review) in reviews.iter().enumerate() {
+      let root = find(&mut parents, i);
+      match groups.iter_mut().find(|(r, _)| *r == root) {
+        Some((_, group)) => group.push(review),
+        None => groups.push((root, vec![review])),
+      }
+    }
+
+    groups
+      .into_iter()
+      .map(|(_, mut group)| {
+        group.sort_by_key(|r| (depths.get(&r._number).copied().unwrap_or_default(), r._number));
+        group
+      })
+      .collect()
+  }
+
+  /// Formats reviews with `review_template`, related changes are formatted as one entry with `related_template`
+  /// showing the first change of the chain which is not ready to submit.
+  pub(crate) fn format_entries(
+    reviews: &[&Review],
+    options: &QueryOptions,
+    config: &Config,
+  ) -> Result<String, PluginError> {
+    let Some(related_template) = &options.related_template else {
+      let mut message = String::new();
+      for review in reviews {
+        message.push_str(&GerritPlugin::format_review_message(
+          review,
+          &options.review_template,
+          config,
+        )?);
+      }
+
+      return Ok(message);
+    };
+
+    let mut message = String::new();
+    for group in GerritPlugin::related_groups(reviews) {
+      if group.len() == 1 {
+        message.push_str(&GerritPlugin::format_review_message(
+          group[0],
+          &options.review_template,
+          config,
+        )?);
+        continue;
+      }
+
+      let blocker = group.iter().find(|r| !r.is_ready());
+      let mut vars = GerritPlugin::review_vars(blocker.unwrap_or(&group[group.len() - 1]), config);
+      vars.insert("count".to_string(), group.len().to_string());
+      vars.insert("blocked".to_string(), blocker.is_some().to_string());
+      vars.insert(
+        "changes".to_string(),
+        group
+          .iter()
+          .map(|r| format!("#{}", r._number))
+          .collect::<Vec<_>>()
+          .join(", "),
+      );
+
+      let entry = GerritPlugin::format_review_vars(related_template, &vars)
+        .map_err(|e| PluginError::Other(format!("Failed to format related template: {}", e)))?;
+      message.push_str(&entry);
+    }
+
+    Ok(message)
+  }
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+
+use octabot_rust_sdk::PluginError;
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded::byte_serialize;
+use waki::Method;
+
+use crate::GerritPlugin;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum Notify {
+  None,
+  Owner,
+  OwnerReviewers,
+  All,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ReviewOptions {
+  pub change: String,
+  pub revision: Option<String>,
+  pub message: Option<String>,
+  #[serde(default)]
+  pub labels: BTreeMap<String, i32>,
+  pub notify: Option<Notify>,
+  pub tag: Option<String>,
+}
+
+/// Body of the set review request, see `ReviewInput` in the Gerrit REST API.
+#[derive(Serialize)]
+struct ReviewInput<'a> {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  message: Option<&'a str>,
+  #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+  labels: &'a BTreeMap<String, i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  notify: Option<Notify>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  tag: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct AbandonInput<'a> {
+  message: &'a str,
+}
+
+#[derive(Serialize)]
+struct ReviewerInput<'a> {
+  reviewer: &'a str,
+}
+
+#[derive(Deserialize)]
+struct AddReviewerResult {
+  error: Option<String>,
+}
+
+impl GerritPlugin {
+  /// Encodes a change identifier for use in a path, e.g. `project~branch~I8473b95934b5732ac55d26311a706c9c2bde9940`.
+  pub(crate) fn encode_id(id: &str) -> String {
+    byte_serialize(id.as_bytes()).collect()
+  }
+
+  /// Posts a review with a message and label votes on a revision, the current one by default.
+  pub(crate) fn post_review(options: &ReviewOptions) -> Result<(), PluginError> {
+    let path = format!(
+      "changes/{}/revisions/{}/review",
+      GerritPlugin::encode_id(&options.change),
+      options.revision.as_deref().unwrap_or("current")
+    );
+
+    let input = ReviewInput {
+      message: options.message.as_deref(),
+      labels: &options.labels,
+      notify: options.notify,
+      tag: options.tag.as_deref(),
+    };
+    let body =
+      serde_json::to_vec(&input).map_err(|e| PluginError::Other(format!("Failed to serialize review: {}", e)))?;
+
+    let client = GerritPlugin::request(Method::Post, &path)?.body(body);
+    let _: serde_json::Value = GerritPlugin::send(client)?;
+
+    Ok(())
+  }
+
+  /// Abandons the change with a message.
+  pub(crate) fn abandon(change: &str, message: &str) -> Result<(), PluginError> {
+    let path = format!("changes/{}/abandon", GerritPlugin::encode_id(change));
+
+    let body = serde_json::to_vec(&AbandonInput { message })
+      .map_err(|e| PluginError::Other(format!("Failed to serialize abandon input: {}", e)))?;
+
+    let client = GerritPlugin::request(Method::Post, &path)?.body(body);
+    let _: serde_json::Value = GerritPlugin::send(client)?;
+
+    Ok(())
+  }
+
+  /// Adds an account or group as a reviewer of the change.
+  pub(crate) fn add_reviewer(change: &str, reviewer: &str) -> Result<(), PluginError> {
+    let path = format!("changes/{}/reviewers", GerritPlugin::encode_id(change));
+
+    let body = serde_json::to_vec(&ReviewerInput { reviewer })
+      .map_err(|e| PluginError::Other(format!("Failed to serialize reviewer: {}", e)))?;
+
+    let client = GerritPlugin::request(Method::Post, &path)?.body(body);
+    let result: AddReviewerResult = GerritPlugin::send(client)?;
+    if let Some(error) = result.error {
+      return Err(PluginError::Other(format!(
+        "Failed to add reviewer {}: {}",
+        reviewer, error
+      )));
+    }
+
+    Ok(())
+  }
+}
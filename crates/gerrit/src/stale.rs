@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use octabot_rust_sdk::{Error, KeyValue, PluginError, PluginResult};
+use serde::{Deserialize, Serialize};
+
+use crate::notification::Notification;
+use crate::review::{Notify, ReviewOptions};
+use crate::{GerritPlugin, ProjectFilter, Review, CONFIG, DEFAULT_MAX_CHANGES, STALE_WARNING_TAG};
+
+#[derive(Serialize, Deserialize)]
+struct StaleReport {
+  channel: String,
+  topic: String,
+  template: String,
+  review_template: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StaleOptions {
+  query: String,
+  #[serde(flatten)]
+  project_filter: ProjectFilter,
+  warn_after_days: u32,
+  abandon_after_days: u32,
+  warning_message: String,
+  abandon_message: String,
+  exempt_hashtag: Option<String>,
+  #[serde(default)]
+  dry_run: bool,
+  report: Option<StaleReport>,
+  max_changes: Option<usize>,
+  notification: Option<Notification>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum StaleAction {
+  Warn,
+  Abandon,
+}
+
+impl StaleAction {
+  fn name(&self) -> &'static str {
+    match self {
+      StaleAction::Warn => "warn",
+      StaleAction::Abandon => "abandon",
+    }
+  }
+}
+
+impl Review {
+  /// Returns the time of the last message other than stale warnings, so the warning itself is not activity.
+  fn last_activity(&self) -> DateTime<Utc> {
+    self
+      .messages
+      .iter()
+      .filter(|m| m.tag.as_deref() != Some(STALE_WARNING_TAG))
+      .map(|m| m.date)
+      .max()
+      .unwrap_or(self.updated)
+  }
+
+  /// Returns the time of the last stale warning as recorded by Gerrit.
+  fn last_warning(&self) -> Option<DateTime<Utc>> {
+    self
+      .messages
+      .iter()
+      .filter(|m| m.tag.as_deref() == Some(STALE_WARNING_TAG))
+      .map(|m| m.date)
+      .max()
+  }
+}
+
+impl GerritPlugin {
+  /// Decides what to do with a change, a warning is dropped once there is activity on the change after it.
+  fn stale_action(
+    review: &Review,
+    warned_at: Option<DateTime<Utc>>,
+    options: &StaleOptions,
+    now: DateTime<Utc>,
+  ) -> Option<StaleAction> {
+    let last_activity = review.last_activity();
+    match warned_at {
+      Some(warned_at) if last_activity <= warned_at => {
+        (now - warned_at >= TimeDelta::days(options.abandon_after_days as i64)).then_some(StaleAction::Abandon)
+      },
+      _ => (now - last_activity >= TimeDelta::days(options.warn_after_days as i64)).then_some(StaleAction::Warn),
+    }
+  }
+
+  /// Warns about changes inactive for `warn_after_days` and abandons them if still inactive `abandon_after_days` after
+  /// the warning, with `dry_run` only the report of planned actions is sent.
+  pub(crate) fn process_stale(task_id: &str, options: &StaleOptions) -> Result<Vec<PluginResult>, Error> {
+    let config = CONFIG
+      .lock()
+      .map_err(|e| PluginError::ConfigLock(e.to_string()))?
+      .clone()
+      .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
+
+    let keyvalue = KeyValue::open()?;
+    let mut warnings: BTreeMap<i32, DateTime<Utc>> = GerritPlugin::load_state(&keyvalue, task_id, "stale")?;
+
+    // Warned changes are updated by the warning comment, so they are fetched separately from inactive ones
+    let max_changes = options.max_changes.unwrap_or(DEFAULT_MAX_CHANGES);
+    let query = format!("({}) age:{}d", options.query, options.warn_after_days);
+    let query = GerritPlugin::build_query(&query, &options.project_filter)?;
+    let mut reviews = GerritPlugin::query_changes_with(&query, max_changes, &["MESSAGES"])?;
+    if !warnings.is_empty() {
+      let query = warnings
+        .keys()
+        .map(|n| format!("change:{}", n))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+      let warned = GerritPlugin::query_changes_with(&query, warnings.len(), &["MESSAGES"])?;
+      let numbers = warned.iter().map(|r| r._number).collect::<Vec<_>>();
+      warnings.retain(|number, _| numbers.contains(number));
+
+      reviews.retain(|r| !numbers.contains(&r._number));
+      reviews.extend(warned);
+    }
+
+    let now = Utc::now();
+    let mut actions: Vec<(&Review, StaleAction)> = vec![];
+    for review in &reviews {
+      let exempt = options
+        .exempt_hashtag
+        .as_ref()
+        .is_some_and(|hashtag| review.hashtags.contains(hashtag));
+      if exempt || review.status != "NEW" {
+        warnings.remove(&review._number);
+        continue;
+      }
+
+      // Times are compared on the Gerrit clock, the time saved when posting the warning is only a fallback
+      let warned_at = warnings
+        .get(&review._number)
+        .map(|saved| review.last_warning().unwrap_or(*saved));
+      match warned_at {
+        Some(warned_at) if review.last_activity() > warned_at => {
+          warnings.remove(&review._number);
+        },
+        Some(warned_at) => {
+          warnings.insert(review._number, warned_at);
+        },
+        None => {},
+      }
+
+      if let Some(action) = GerritPlugin::stale_action(review, warned_at, options, now) {
+        actions.push((review, action));
+      }
+    }
+
+    if !options.dry_run {
+      for (review, action) in &actions {
+        match action {
+          StaleAction::Warn => {
+            let message = GerritPlugin::format_review_message(review, &options.warning_message, &config)?;
+            GerritPlugin::post_review(&ReviewOptions {
+              change: review.id.clone(),
+              revision: None,
+              message: Some(message),
+              labels: BTreeMap::new(),
+              notify: Some(Notify::OwnerReviewers),
+              tag: Some(STALE_WARNING_TAG.to_string()),
+            })?;
+            warnings.insert(review._number, Utc::now());
+          },
+          StaleAction::Abandon => {
+            let message = GerritPlugin::format_review_message(review, &options.abandon_message, &config)?;
+            GerritPlugin::abandon(&review.id, &message)?;
+            warnings.remove(&review._number);
+          },
+        }
+      }
+
+      GerritPlugin::save_state(&keyvalue, task_id, "stale", &warnings)?;
+    }
+
+    let Some(report) = &options.report else {
+      return Ok(vec![]);
+    };
+    if actions.is_empty() {
+      return Ok(vec![]);
+    }
+
+    let mut message = report.template.clone();
+    for (review, action) in actions {
+      let mut vars = GerritPlugin::review_vars(review, &config);
+      vars.insert("action".to_string(), action.name().to_string());
+      let line = GerritPlugin::format_review_vars(&report.review_template, &vars)
+        .map_err(|e| PluginError::Other(format!("Failed to format stale report template: {}", e)))?;
+      message.push_str(&line);
+    }
+
+    Ok(vec![GerritPlugin::stream_notification(
+      task_id,
+      options.notification.as_ref(),
+      &report.channel,
+      &report.topic,
+      message,
+    )?])
+  }
+}
@@ -1,21 +1,73 @@
 #![allow(deprecated)]
-use std::time::Duration;
-use std::{num::NonZeroU32, sync::Mutex};
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, num::NonZeroU32, sync::Mutex};
 
 use base64::encode;
-use governor::{clock::DefaultClock, state::keyed::DefaultKeyedStateStore, Quota, RateLimiter};
-use octabot_rust_sdk::{wit::export, Error, Metadata, Plugin, PluginError, PluginResult};
+use chrono::{NaiveTime, Utc};
+use chrono_tz::Tz;
+use octabot_rust_sdk::{wit::export, ActionData, Error, KeyValue, Metadata, Plugin, PluginError, PluginResult};
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
 use url::Url;
 use waki::{Client, Method, RequestBuilder};
 
-type Limiter = RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock>;
+type Limiters = Vec<(LimitKey, Limiter)>;
 
-static RATE_LIMITER: Lazy<Mutex<Option<Limiter>>> = Lazy::new(|| Mutex::new(None));
+static RATE_LIMITERS: Lazy<Mutex<Option<Limiters>>> = Lazy::new(|| Mutex::new(None));
 
 static CONFIG: Lazy<Mutex<Option<Config>>> = Lazy::new(|| Mutex::new(None));
 
+const HELD_MESSAGES_KEY: &str = "zulip:quiet_hours:held";
+
+const COALESCED_MESSAGES_KEY: &str = "zulip:coalesce:queued";
+
+const ESCALATIONS_KEY: &str = "zulip:escalations";
+
+const DEFAULT_ACKNOWLEDGE_EMOJI: &str = "check";
+
+const DIGEST_SEPARATOR: &str = "\n\n";
+
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+
+const INVALID_MESSAGE_ERROR: &str = "Invalid message(s)";
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Destination {
+  pub channel: String,
+  pub topic: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct QuietHours {
+  #[serde(flatten)]
+  pub destination: Destination,
+  pub start: String,
+  pub end: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Coalesce {
+  #[serde(flatten)]
+  pub destination: Destination,
+  pub window: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum LimitKey {
+  Global,
+  Stream,
+  StreamTopic,
+  Task,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RateLimit {
+  pub key: LimitKey,
+  pub max_request_in_minute: u32,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct Config {
   pub endpoint: String,
@@ -23,33 +75,307 @@ struct Config {
   pub login: String,
   pub password: String,
   pub max_request_in_minute: u32,
+  pub timezone: Option<String>,
+  #[serde(default)]
+  pub quiet_hours: Vec<QuietHours>,
+  #[serde(default)]
+  pub coalesce: Vec<Coalesce>,
+  #[serde(default)]
+  pub rate_limits: Vec<RateLimit>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum EscalationTarget {
+  Stream { channel: String, topic: String },
+  User { user: String },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Escalation {
+  #[serde(flatten)]
+  target: EscalationTarget,
+  after: u64,
+  emoji: Option<String>,
+  message: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Message {
+  #[serde(default)]
   channel: String,
+  #[serde(default)]
   topic: String,
+  user: Option<String>,
   message: String,
+  #[serde(default)]
+  urgent: bool,
+  escalation: Option<Escalation>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct MessageFilter {
+  channel: String,
+  topic: Option<String>,
+  #[serde(default)]
+  sent_by_bot: bool,
+  search: Option<String>,
+  limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LookupOptions {
+  #[serde(flatten)]
+  filter: MessageFilter,
+  reply_action: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum Operation {
+  #[default]
+  Post,
+  Lookup,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Params {
   task_id: String,
-  options: Message,
+  #[serde(default)]
+  operation: Operation,
+  options: serde_json::Value,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 struct PostMessageResponse {
-  id: i32,
+  id: i64,
   msg: String,
   result: String,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+struct Reaction {
+  emoji_name: String,
+  user_id: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ZulipMessage {
+  id: i64,
+  subject: String,
+  content: String,
+  timestamp: i64,
+  #[serde(default)]
+  reactions: Vec<Reaction>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct GetMessageResponse {
+  msg: String,
+  result: String,
+  message: Option<ZulipMessage>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct GetMessagesResponse {
+  msg: String,
+  result: String,
+  #[serde(default)]
+  messages: Vec<ZulipMessage>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct HeldMessage {
+  channel: String,
+  topic: String,
+  message: String,
+  #[serde(default)]
+  task_id: Option<String>,
+  #[serde(default)]
+  escalation: Option<Escalation>,
+  #[serde(default)]
+  attempts: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct QueuedMessage {
+  message: String,
+  #[serde(default)]
+  task_id: Option<String>,
+  #[serde(default)]
+  escalation: Option<Escalation>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct TrackedAlert {
+  message_ids: Vec<i64>,
+  #[serde(default)]
+  task_id: Option<String>,
+  checked_at: i64,
+  message: String,
+  escalation: Escalation,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct CoalescedMessages {
+  channel: String,
+  topic: String,
+  opened_at: i64,
+  window: u64,
+  messages: Vec<QueuedMessage>,
+  #[serde(default)]
+  attempts: u32,
+}
+
+/// Keyed token bucket allowing `per_minute` requests per minute with bursts of the same size.
+struct Limiter {
+  per_minute: NonZeroU32,
+  buckets: HashMap<String, (f64, Instant)>,
+}
+
+impl Limiter {
+  fn new(per_minute: NonZeroU32) -> Self {
+    Limiter {
+      per_minute,
+      buckets: HashMap::new(),
+    }
+  }
+
+  fn interval(&self) -> Duration {
+    Duration::from_secs(60) / self.per_minute.get()
+  }
+
+  fn tokens(&self, key: &str, now: Instant) -> f64 {
+    let capacity = self.per_minute.get() as f64;
+    match self.buckets.get(key) {
+      Some((tokens, updated)) => {
+        let refilled = now.duration_since(*updated).as_secs_f64() / self.interval().as_secs_f64();
+        (tokens + refilled).min(capacity)
+      },
+      None => capacity,
+    }
+  }
+
+  /// Returns how long a request for the key has to wait, zero when it's allowed now. No token is taken.
+  fn wait_time(&self, key: &str, now: Instant) -> Duration {
+    let tokens = self.tokens(key, now);
+    if tokens >= 1.0 {
+      Duration::ZERO
+    } else {
+      self.interval().mul_f64(1.0 - tokens)
+    }
+  }
+
+  fn take(&mut self, key: &str, now: Instant) {
+    let tokens = self.tokens(key, now) - 1.0;
+    self.buckets.insert(key.to_string(), (tokens, now));
+  }
+}
+
+impl TrackedAlert {
+  /// Creates an alert for a posted message, messages delivered in a digest are tracked by the digest id.
+  fn new(task_id: &str, message_id: i64, message: String, escalation: Escalation) -> Self {
+    TrackedAlert {
+      message_ids: vec![message_id],
+      task_id: Some(task_id.to_string()),
+      checked_at: Utc::now().timestamp(),
+      message,
+      escalation,
+    }
+  }
+}
+
+impl LimitKey {
+  /// Returns the limiter key for a request, requests without a destination are not limited per stream.
+  fn key(&self, task_id: &str, destination: Option<(&str, &str)>) -> Option<String> {
+    match (self, destination) {
+      (LimitKey::Global, _) => Some("global".to_string()),
+      (LimitKey::Task, _) => Some(task_id.to_string()),
+      (LimitKey::Stream, Some((channel, _))) => Some(channel.to_string()),
+      (LimitKey::StreamTopic, Some((channel, topic))) => Some(format!("{}/{}", channel, topic)),
+      (_, None) => None,
+    }
+  }
+}
+
+impl Destination {
+  fn matches(&self, channel: &str, topic: &str) -> bool {
+    self.channel == channel && self.topic.as_ref().is_none_or(|t| t == topic)
+  }
+}
+
+impl QuietHours {
+  fn contains(&self, time: NaiveTime) -> Result<bool, PluginError> {
+    let start = ZulipPlugin::parse_time(&self.start)?;
+    let end = ZulipPlugin::parse_time(&self.end)?;
+
+    // Window may wrap around midnight, e.g. 22:00 - 08:00
+    if start <= end {
+      Ok(time >= start && time < end)
+    } else {
+      Ok(time >= start || time < end)
+    }
+  }
+}
+
 struct ZulipPlugin;
 
 impl ZulipPlugin {
-  fn request(key: String, path: &str) -> Result<RequestBuilder, PluginError> {
-    let limiter = RATE_LIMITER
+  /// Returns the first limit the request would exceed with the time to wait, no token is taken.
+  fn exceeded_limit(
+    limiters: &Limiters,
+    task_id: &str,
+    destination: Option<(&str, &str)>,
+    now: Instant,
+  ) -> Option<(LimitKey, Duration)> {
+    limiters.iter().find_map(|(limit_key, limiter)| {
+      let key = limit_key.key(task_id, destination)?;
+      let wait_time = limiter.wait_time(&key, now);
+      (!wait_time.is_zero()).then_some((*limit_key, wait_time))
+    })
+  }
+
+  /// Takes a token from every limiter once all of them allow the request, so a rejected request doesn't use up the
+  /// other quotas.
+  fn check_limits(
+    limiters: &mut Limiters,
+    task_id: &str,
+    destination: Option<(&str, &str)>,
+    now: Instant,
+  ) -> Result<(), PluginError> {
+    if let Some((limit_key, wait_time)) = ZulipPlugin::exceeded_limit(limiters, task_id, destination, now) {
+      return Err(PluginError::Other(format!(
+        "Rate limit exceeded for {:?} key. Try again in {} seconds",
+        limit_key,
+        wait_time.as_secs_f64().ceil()
+      )));
+    }
+
+    for (limit_key, limiter) in limiters.iter_mut() {
+      if let Some(key) = limit_key.key(task_id, destination) {
+        limiter.take(&key, now);
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Checks whether catch-up work queued by a task can be sent now without exceeding its rate limits.
+  fn has_quota(task_id: &str, destination: Option<(&str, &str)>) -> bool {
+    RATE_LIMITERS.lock().is_ok_and(|limiters| {
+      limiters
+        .as_ref()
+        .is_some_and(|limiters| ZulipPlugin::exceeded_limit(limiters, task_id, destination, Instant::now()).is_none())
+    })
+  }
+
+  fn request(
+    method: Method,
+    task_id: &str,
+    destination: Option<(&str, &str)>,
+    path: &str,
+  ) -> Result<RequestBuilder, PluginError> {
+    let mut limiters = RATE_LIMITERS
       .lock()
       .map_err(|e| PluginError::Other(format!("Can't lock rate limiter: {}", e)))?;
     let config = CONFIG
@@ -58,16 +384,8 @@ impl ZulipPlugin {
       .clone()
       .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
 
-    if let Some(limiter) = limiter.as_ref() {
-      // Check rate limit
-      if let Err(not_until) = limiter.check_key(&key) {
-        return Err(PluginError::Other(format!(
-          "Rate limit exceeded. Try again in {} seconds",
-          not_until
-            .wait_time_from(governor::clock::Clock::now(limiter.clock()))
-            .as_secs()
-        )));
-      }
+    if let Some(limiters) = limiters.as_mut() {
+      ZulipPlugin::check_limits(limiters, task_id, destination, Instant::now())?;
 
       let url = Url::parse(&format!("{}/{}", &config.endpoint, path)).map_err(|e| PluginError::Other(e.to_string()))?;
 
@@ -75,7 +393,7 @@ impl ZulipPlugin {
       let authorization = encode(credentials);
 
       let client = Client::new()
-        .request(Method::Post, url.as_str())
+        .request(method, url.as_str())
         .connect_timeout(Duration::from_secs(config.timeout.unwrap_or(60)))
         .headers([
           ("Content-Type", "application/json"),
@@ -87,59 +405,609 @@ impl ZulipPlugin {
       Err(PluginError::Other("Rate limiter not initialized".to_string()))
     }
   }
-}
-
-impl Plugin for ZulipPlugin {
-  fn process(payload: String) -> Result<Vec<PluginResult>, Error> {
-    let params = serde_json::from_str::<Params>(&payload)
-      .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse zulip params: {}", err)))?;
 
+  fn post_message(task_id: &str, channel: &str, topic: &str, content: &str) -> Result<i64, PluginError> {
     let query = [
       ("type", "stream"),
-      ("to", &params.options.channel),
-      ("topic", &params.options.topic),
-      ("content", &params.options.message),
+      ("to", channel),
+      ("topic", topic),
+      ("content", content),
     ];
 
-    let client = ZulipPlugin::request(params.task_id, "api/v1/messages")?.query(&query);
+    let client = ZulipPlugin::request(Method::Post, task_id, Some((channel, topic)), "api/v1/messages")?.query(&query);
+
+    let resp: PostMessageResponse = ZulipPlugin::send(client)?;
+    if resp.result != "success" {
+      return Err(PluginError::Other(resp.msg));
+    }
+
+    Ok(resp.id)
+  }
+
+  fn post_direct_message(task_id: &str, user: &str, content: &str) -> Result<i64, PluginError> {
+    let query = [("type", "private"), ("to", user), ("content", content)];
+
+    let client = ZulipPlugin::request(Method::Post, task_id, None, "api/v1/messages")?.query(&query);
+
+    let resp: PostMessageResponse = ZulipPlugin::send(client)?;
+    if resp.result != "success" {
+      return Err(PluginError::Other(resp.msg));
+    }
+
+    Ok(resp.id)
+  }
+
+  /// Returns `None` when Zulip rejects the message id, e.g. the message was deleted.
+  fn get_message(task_id: &str, message_id: i64) -> Result<Option<ZulipMessage>, PluginError> {
+    let path = format!("api/v1/messages/{}", message_id);
+    let client = ZulipPlugin::request(Method::Get, task_id, None, &path)?.query(&[("apply_markdown", "false")]);
+
+    // Error responses are parsed too, to tell a missing message apart from other failures
+    let resp = client.send().map_err(|e| PluginError::SendHttpRequest(e.to_string()))?;
+    let code = resp.status_code();
+    let body = resp.body().map_err(|e| PluginError::ParseResponse(e.to_string()))?;
+    let resp = match serde_json::from_slice::<GetMessageResponse>(&body) {
+      Ok(resp) => resp,
+      Err(_) if code != 200 => return Err(PluginError::SendHttpRequest(format!("HTTP/{}", code))),
+      Err(e) => return Err(PluginError::ParseResponse(e.to_string())),
+    };
+    if (400..500).contains(&code) && resp.msg == INVALID_MESSAGE_ERROR {
+      return Ok(None);
+    }
+    if code != 200 || resp.result != "success" {
+      return Err(PluginError::Other(resp.msg));
+    }
+
+    resp
+      .message
+      .map(Some)
+      .ok_or_else(|| PluginError::ParseResponse("Missing message in response".to_string()))
+  }
+
+  /// Searches channel history with a narrow built from the filter, returns up to `limit` latest matching messages.
+  fn find_messages(task_id: &str, filter: &MessageFilter) -> Result<Vec<ZulipMessage>, PluginError> {
+    let config = CONFIG
+      .lock()
+      .map_err(|e| PluginError::ConfigLock(e.to_string()))?
+      .clone()
+      .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
+
+    let mut narrow = vec![json!({ "operator": "stream", "operand": filter.channel })];
+    if let Some(topic) = &filter.topic {
+      narrow.push(json!({ "operator": "topic", "operand": topic }));
+    }
+    if filter.sent_by_bot {
+      narrow.push(json!({ "operator": "sender", "operand": config.login }));
+    }
+    if let Some(search) = &filter.search {
+      narrow.push(json!({ "operator": "search", "operand": search }));
+    }
+
+    let narrow = serde_json::Value::Array(narrow).to_string();
+    let limit = filter.limit.unwrap_or(100).to_string();
+    let query = [
+      ("anchor", "newest"),
+      ("num_before", &limit),
+      ("num_after", "0"),
+      ("apply_markdown", "false"),
+      ("narrow", &narrow),
+    ];
+
+    let topic = filter.topic.as_deref().unwrap_or_default();
+    let client =
+      ZulipPlugin::request(Method::Get, task_id, Some((&filter.channel, topic)), "api/v1/messages")?.query(&query);
+
+    let resp: GetMessagesResponse = ZulipPlugin::send(client)?;
+    if resp.result != "success" {
+      return Err(PluginError::Other(resp.msg));
+    }
+
+    Ok(resp.messages)
+  }
+
+  fn process_message(task_id: &str, message: Message) -> Result<Vec<PluginResult>, Error> {
+    let config = CONFIG
+      .lock()
+      .map_err(|e| PluginError::ConfigLock(e.to_string()))?
+      .clone()
+      .ok_or_else(|| PluginError::Other("Config not initialized".to_string()))?;
+
+    let tz = ZulipPlugin::parse_timezone(&config)?;
+    let time = Utc::now().with_timezone(&tz).time();
+
+    let keyvalue = KeyValue::open()?;
+    // Undelivered held messages must not stop the incoming message
+    if let Err(e) = ZulipPlugin::flush_held_messages(&keyvalue, &config, task_id, time) {
+      println!("Failed to flush held messages: {:?}", e);
+    }
+    if let Err(e) = ZulipPlugin::flush_coalesced_messages(&keyvalue, task_id) {
+      println!("Failed to flush coalesced messages: {:?}", e);
+    }
+
+    let result = ZulipPlugin::deliver_message(&keyvalue, &config, task_id, message, time);
+
+    // Escalations are checked after the incoming message is handled, so failing checks can't block it
+    if let Err(e) = ZulipPlugin::check_escalations(&keyvalue, &config, task_id) {
+      println!("Failed to check escalations: {:?}", e);
+    }
+
+    result.map(|_| vec![])
+  }
+
+  /// Posts the incoming message, holds it for quiet hours or queues it for coalescing.
+  fn deliver_message(
+    keyvalue: &KeyValue,
+    config: &Config,
+    task_id: &str,
+    message: Message,
+    time: NaiveTime,
+  ) -> Result<(), Error> {
+    // Empty message only delivers held and coalesced messages
+    if message.message.is_empty() {
+      return Ok(());
+    }
+
+    // Quiet hours and coalescing are set per channel, direct messages are posted immediately
+    let direct = message.user.is_some();
+    if !message.urgent && !direct && ZulipPlugin::is_quiet(config, &message.channel, &message.topic, time)? {
+      let mut held = ZulipPlugin::load_held_messages(keyvalue)?;
+      held.push(HeldMessage {
+        channel: message.channel,
+        topic: message.topic,
+        message: message.message,
+        task_id: Some(task_id.to_string()),
+        escalation: message.escalation,
+        attempts: 0,
+      });
+      ZulipPlugin::save_held_messages(keyvalue, &held)?;
+
+      return Ok(());
+    }
+
+    if !message.urgent && !direct {
+      if let Some(window) = ZulipPlugin::coalesce_window(config, &message.channel, &message.topic) {
+        let queued = QueuedMessage {
+          message: message.message,
+          task_id: Some(task_id.to_string()),
+          escalation: message.escalation,
+        };
+        ZulipPlugin::coalesce_message(keyvalue, message.channel, message.topic, queued, window)?;
+
+        return Ok(());
+      }
+    }
+
+    let message_id = match &message.user {
+      Some(user) => ZulipPlugin::post_direct_message(task_id, user, &message.message)?,
+      None => ZulipPlugin::post_message(task_id, &message.channel, &message.topic, &message.message)?,
+    };
+
+    if let Some(escalation) = message.escalation {
+      ZulipPlugin::track_alerts(
+        keyvalue,
+        vec![TrackedAlert::new(task_id, message_id, message.message, escalation)],
+      )?;
+    }
+
+    Ok(())
+  }
 
-    let resp = match client.send() {
+  fn send<T: DeserializeOwned>(client: RequestBuilder) -> Result<T, PluginError> {
+    match client.send() {
       Ok(resp) => match resp.status_code() {
         200 => match String::from_utf8(resp.body().unwrap()) {
-          Ok(resp) => {
-            serde_json::from_str::<PostMessageResponse>(&resp).map_err(|e| PluginError::ParseResponse(e.to_string()))?
-          },
-          Err(e) => return Err(PluginError::ParseResponse(e.to_string()).into()),
+          Ok(resp) => serde_json::from_str::<T>(&resp).map_err(|e| PluginError::ParseResponse(e.to_string())),
+          Err(e) => Err(PluginError::ParseResponse(e.to_string())),
         },
-        code => return Err(PluginError::SendHttpRequest(format!("HTTP/{}", code)).into()),
+        code => Err(PluginError::SendHttpRequest(format!("HTTP/{}", code))),
       },
-      Err(e) => return Err(PluginError::SendHttpRequest(e.to_string()).into()),
+      Err(e) => Err(PluginError::SendHttpRequest(e.to_string())),
+    }
+  }
+
+  fn parse_time(value: &str) -> Result<NaiveTime, PluginError> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+      .map_err(|e| PluginError::Other(format!("Invalid quiet hours time '{}': {}", value, e)))
+  }
+
+  fn parse_timezone(config: &Config) -> Result<Tz, PluginError> {
+    match &config.timezone {
+      Some(timezone) => timezone
+        .parse()
+        .map_err(|e| PluginError::Other(format!("Invalid timezone: {}", e))),
+      None => Ok(Tz::UTC),
+    }
+  }
+
+  fn is_quiet(config: &Config, channel: &str, topic: &str, time: NaiveTime) -> Result<bool, PluginError> {
+    for quiet_hours in config
+      .quiet_hours
+      .iter()
+      .filter(|q| q.destination.matches(channel, topic))
+    {
+      if quiet_hours.contains(time)? {
+        return Ok(true);
+      }
+    }
+
+    Ok(false)
+  }
+
+  fn load_held_messages(keyvalue: &KeyValue) -> Result<Vec<HeldMessage>, Error> {
+    match keyvalue.get(HELD_MESSAGES_KEY)? {
+      Some(data) => Ok(
+        serde_json::from_slice(&data)
+          .map_err(|e| PluginError::Other(format!("Failed to parse held messages: {}", e)))?,
+      ),
+      None => Ok(vec![]),
+    }
+  }
+
+  fn save_held_messages(keyvalue: &KeyValue, messages: &[HeldMessage]) -> Result<(), Error> {
+    let data = serde_json::to_vec(messages)
+      .map_err(|e| PluginError::Other(format!("Failed to serialize held messages: {}", e)))?;
+    keyvalue.set(HELD_MESSAGES_KEY, &data)?;
+
+    Ok(())
+  }
+
+  /// Delivers messages held for destinations whose quiet hours are over, one digest per channel and topic. A digest is
+  /// sent on behalf of the task that held its first message, `task_id` is only used for messages held before tasks were
+  /// recorded. Digests over the rate limit wait for the next run, failing ones are retried up to `MAX_DELIVERY_ATTEMPTS`
+  /// times.
+  fn flush_held_messages(keyvalue: &KeyValue, config: &Config, task_id: &str, time: NaiveTime) -> Result<(), Error> {
+    let held = ZulipPlugin::load_held_messages(keyvalue)?;
+    if held.is_empty() {
+      return Ok(());
+    }
+
+    let mut remaining = vec![];
+    let mut digests: Vec<(String, String, Vec<HeldMessage>)> = vec![];
+    for message in held {
+      if ZulipPlugin::is_quiet(config, &message.channel, &message.topic, time)? {
+        remaining.push(message);
+        continue;
+      }
+
+      match digests
+        .iter_mut()
+        .find(|(channel, topic, _)| *channel == message.channel && *topic == message.topic)
+      {
+        Some((_, _, messages)) => messages.push(message),
+        None => digests.push((message.channel.clone(), message.topic.clone(), vec![message])),
+      }
+    }
+
+    let mut alerts = vec![];
+    for (channel, topic, messages) in digests {
+      let origin = messages[0].task_id.clone().unwrap_or_else(|| task_id.to_string());
+      if !ZulipPlugin::has_quota(&origin, Some((&channel, &topic))) {
+        remaining.extend(messages);
+        continue;
+      }
+
+      let content = messages
+        .iter()
+        .map(|m| m.message.as_str())
+        .collect::<Vec<_>>()
+        .join(DIGEST_SEPARATOR);
+      match ZulipPlugin::post_message(&origin, &channel, &topic, &content) {
+        Ok(message_id) => {
+          alerts.extend(messages.into_iter().filter_map(|m| {
+            let task_id = m.task_id.as_deref().unwrap_or(&origin);
+            m.escalation
+              .map(|escalation| TrackedAlert::new(task_id, message_id, m.message, escalation))
+          }));
+        },
+        Err(e) => {
+          println!("Failed to deliver held messages to {}/{}: {}", channel, topic, e);
+          for mut message in messages {
+            message.attempts += 1;
+            if message.attempts < MAX_DELIVERY_ATTEMPTS {
+              remaining.push(message);
+            } else {
+              println!(
+                "Dropping held message to {}/{} after {} attempts",
+                channel, topic, message.attempts
+              );
+            }
+          }
+        },
+      }
+    }
+
+    ZulipPlugin::save_held_messages(keyvalue, &remaining)?;
+    ZulipPlugin::track_alerts(keyvalue, alerts)
+  }
+
+  fn load_tracked_alerts(keyvalue: &KeyValue) -> Result<Vec<TrackedAlert>, Error> {
+    match keyvalue.get(ESCALATIONS_KEY)? {
+      Some(data) => Ok(
+        serde_json::from_slice(&data)
+          .map_err(|e| PluginError::Other(format!("Failed to parse tracked alerts: {}", e)))?,
+      ),
+      None => Ok(vec![]),
+    }
+  }
+
+  fn save_tracked_alerts(keyvalue: &KeyValue, alerts: &[TrackedAlert]) -> Result<(), Error> {
+    let data = serde_json::to_vec(alerts)
+      .map_err(|e| PluginError::Other(format!("Failed to serialize tracked alerts: {}", e)))?;
+    keyvalue.set(ESCALATIONS_KEY, &data)?;
+
+    Ok(())
+  }
+
+  /// Starts tracking posted messages for escalation.
+  fn track_alerts(keyvalue: &KeyValue, new_alerts: Vec<TrackedAlert>) -> Result<(), Error> {
+    if new_alerts.is_empty() {
+      return Ok(());
+    }
+
+    let mut alerts = ZulipPlugin::load_tracked_alerts(keyvalue)?;
+    alerts.extend(new_alerts);
+
+    ZulipPlugin::save_tracked_alerts(keyvalue, &alerts)
+  }
+
+  /// Checks the reactions of the posted messages, a deleted message stops the escalation like an acknowledgement.
+  fn is_acknowledged(task_id: &str, alert: &TrackedAlert) -> Result<bool, PluginError> {
+    let emoji = alert.escalation.emoji.as_deref().unwrap_or(DEFAULT_ACKNOWLEDGE_EMOJI);
+    for message_id in &alert.message_ids {
+      let Some(message) = ZulipPlugin::get_message(task_id, *message_id)? else {
+        println!("Message {} not found, stopping its escalation", message_id);
+        return Ok(true);
+      };
+      if message.reactions.iter().any(|r| r.emoji_name == emoji) {
+        return Ok(true);
+      }
+    }
+
+    Ok(false)
+  }
+
+  fn escalate(task_id: &str, config: &Config, alert: &TrackedAlert) -> Result<i64, PluginError> {
+    let content = match &alert.escalation.message {
+      Some(message) => message.clone(),
+      None => format!(
+        "Not acknowledged in {} minutes, see [original message]({}/#narrow/near/{}):\n\n{}",
+        alert.escalation.after, config.endpoint, alert.message_ids[0], alert.message
+      ),
     };
 
-    if resp.result != "success" {
-      return Err(PluginError::Other(resp.msg).into());
+    match &alert.escalation.target {
+      EscalationTarget::Stream { channel, topic } => ZulipPlugin::post_message(task_id, channel, topic, &content),
+      EscalationTarget::User { user } => ZulipPlugin::post_direct_message(task_id, user, &content),
+    }
+  }
+
+  /// Re-posts alerts nobody acknowledged with a reaction to their escalation destination, every `after` minutes
+  /// until one of the posted messages is acknowledged. Alerts are checked on behalf of the task that posted them and
+  /// wait for the next run when its rate limit is used up or the check fails.
+  fn check_escalations(keyvalue: &KeyValue, config: &Config, task_id: &str) -> Result<(), Error> {
+    let alerts = ZulipPlugin::load_tracked_alerts(keyvalue)?;
+    if alerts.is_empty() {
+      return Ok(());
     }
 
-    Ok(vec![])
+    let now = Utc::now().timestamp();
+    let mut remaining = vec![];
+    for mut alert in alerts {
+      let due = now - alert.checked_at >= (alert.escalation.after * 60) as i64;
+      let origin = alert.task_id.clone().unwrap_or_else(|| task_id.to_string());
+      let destination = match &alert.escalation.target {
+        EscalationTarget::Stream { channel, topic } => Some((channel.as_str(), topic.as_str())),
+        EscalationTarget::User { .. } => None,
+      };
+      if !due || !ZulipPlugin::has_quota(&origin, destination) {
+        remaining.push(alert);
+        continue;
+      }
+
+      match ZulipPlugin::is_acknowledged(&origin, &alert) {
+        Ok(true) => continue,
+        Ok(false) => match ZulipPlugin::escalate(&origin, config, &alert) {
+          Ok(message_id) => {
+            alert.message_ids.push(message_id);
+            alert.checked_at = now;
+          },
+          Err(e) => println!("Failed to escalate message {}: {}", alert.message_ids[0], e),
+        },
+        Err(e) => println!(
+          "Failed to check acknowledgement of message {}: {}",
+          alert.message_ids[0], e
+        ),
+      }
+
+      remaining.push(alert);
+    }
+
+    ZulipPlugin::save_tracked_alerts(keyvalue, &remaining)
+  }
+
+  fn coalesce_window(config: &Config, channel: &str, topic: &str) -> Option<u64> {
+    config
+      .coalesce
+      .iter()
+      .find(|c| c.destination.matches(channel, topic))
+      .map(|c| c.window)
+  }
+
+  fn load_coalesced_messages(keyvalue: &KeyValue) -> Result<Vec<CoalescedMessages>, Error> {
+    match keyvalue.get(COALESCED_MESSAGES_KEY)? {
+      Some(data) => Ok(
+        serde_json::from_slice(&data)
+          .map_err(|e| PluginError::Other(format!("Failed to parse coalesced messages: {}", e)))?,
+      ),
+      None => Ok(vec![]),
+    }
+  }
+
+  fn save_coalesced_messages(keyvalue: &KeyValue, messages: &[CoalescedMessages]) -> Result<(), Error> {
+    let data = serde_json::to_vec(messages)
+      .map_err(|e| PluginError::Other(format!("Failed to serialize coalesced messages: {}", e)))?;
+    keyvalue.set(COALESCED_MESSAGES_KEY, &data)?;
+
+    Ok(())
+  }
+
+  fn coalesce_message(
+    keyvalue: &KeyValue,
+    channel: String,
+    topic: String,
+    message: QueuedMessage,
+    window: u64,
+  ) -> Result<(), Error> {
+    let mut coalesced = ZulipPlugin::load_coalesced_messages(keyvalue)?;
+    match coalesced.iter_mut().find(|c| c.channel == channel && c.topic == topic) {
+      Some(digest) => digest.messages.push(message),
+      None => coalesced.push(CoalescedMessages {
+        channel,
+        topic,
+        opened_at: Utc::now().timestamp(),
+        window,
+        messages: vec![message],
+        attempts: 0,
+      }),
+    }
+
+    ZulipPlugin::save_coalesced_messages(keyvalue, &coalesced)
+  }
+
+  /// Posts messages queued for a channel and topic as a single message once their coalescing window is over. Digests
+  /// are sent on behalf of a task like held ones, over the rate limit they wait for the next run and failing ones are
+  /// retried up to `MAX_DELIVERY_ATTEMPTS` times.
+  fn flush_coalesced_messages(keyvalue: &KeyValue, task_id: &str) -> Result<(), Error> {
+    let coalesced = ZulipPlugin::load_coalesced_messages(keyvalue)?;
+    if coalesced.is_empty() {
+      return Ok(());
+    }
+
+    let now = Utc::now().timestamp();
+    let mut remaining = vec![];
+    let mut alerts = vec![];
+    for mut digest in coalesced {
+      let expired = now - digest.opened_at >= digest.window as i64;
+      if !expired {
+        remaining.push(digest);
+        continue;
+      }
+
+      let origin = digest.messages[0]
+        .task_id
+        .clone()
+        .unwrap_or_else(|| task_id.to_string());
+      if !ZulipPlugin::has_quota(&origin, Some((&digest.channel, &digest.topic))) {
+        remaining.push(digest);
+        continue;
+      }
+
+      let content = digest
+        .messages
+        .iter()
+        .map(|m| m.message.as_str())
+        .collect::<Vec<_>>()
+        .join(DIGEST_SEPARATOR);
+      match ZulipPlugin::post_message(&origin, &digest.channel, &digest.topic, &content) {
+        Ok(message_id) => {
+          alerts.extend(digest.messages.into_iter().filter_map(|m| {
+            let task_id = m.task_id.as_deref().unwrap_or(&origin);
+            m.escalation
+              .map(|escalation| TrackedAlert::new(task_id, message_id, m.message, escalation))
+          }));
+        },
+        Err(e) => {
+          println!(
+            "Failed to deliver coalesced messages to {}/{}: {}",
+            digest.channel, digest.topic, e
+          );
+          digest.attempts += 1;
+          if digest.attempts < MAX_DELIVERY_ATTEMPTS {
+            remaining.push(digest);
+          } else {
+            println!(
+              "Dropping coalesced messages to {}/{} after {} attempts",
+              digest.channel, digest.topic, digest.attempts
+            );
+          }
+        },
+      }
+    }
+
+    ZulipPlugin::save_coalesced_messages(keyvalue, &remaining)?;
+    ZulipPlugin::track_alerts(keyvalue, alerts)
+  }
+}
+
+impl Plugin for ZulipPlugin {
+  fn process(payload: String) -> Result<Vec<PluginResult>, Error> {
+    let params = serde_json::from_str::<Params>(&payload)
+      .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse zulip params: {}", err)))?;
+
+    match params.operation {
+      Operation::Post => {
+        let message = serde_json::from_value::<Message>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse zulip message: {}", err)))?;
+
+        ZulipPlugin::process_message(&params.task_id, message)
+      },
+      Operation::Lookup => {
+        let options = serde_json::from_value::<LookupOptions>(params.options)
+          .map_err(|err| PluginError::ParseActionPaylod(format!("unable to parse zulip lookup options: {}", err)))?;
+
+        let messages = ZulipPlugin::find_messages(&params.task_id, &options.filter)?;
+        let action = ActionData {
+          name: options.reply_action,
+          payload: json!({
+            "task_id": params.task_id,
+            "options": {
+              "channel": options.filter.channel,
+              "topic": options.filter.topic,
+              "message_ids": messages.iter().map(|m| m.id).collect::<Vec<_>>(),
+            }
+          })
+          .to_string(),
+        };
+
+        Ok(vec![PluginResult::Action(action)])
+      },
+    }
   }
 
   fn init(config: String) -> Result<(), Error> {
     let config = serde_json::from_str::<Config>(&config).map_err(|err| PluginError::ParseBotConfig(err.to_string()))?;
 
+    ZulipPlugin::parse_timezone(&config)?;
+    for quiet_hours in &config.quiet_hours {
+      ZulipPlugin::parse_time(&quiet_hours.start)?;
+      ZulipPlugin::parse_time(&quiet_hours.end)?;
+    }
+
     let mut global_config = CONFIG.lock().map_err(|e| PluginError::ConfigLock(e.to_string()))?;
     *global_config = Some(config.clone());
 
-    let mut limiter = RATE_LIMITER.lock().map_err(|e| PluginError::Other(e.to_string()))?;
-    if limiter.is_none() {
-      let rate = NonZeroU32::new(config.max_request_in_minute.max(1))
-        .ok_or_else(|| PluginError::Other("Invalid rate limit value".to_string()))?;
+    let mut limiters = RATE_LIMITERS.lock().map_err(|e| PluginError::Other(e.to_string()))?;
+    if limiters.is_none() {
+      let mut rate_limits = vec![RateLimit {
+        key: LimitKey::Task,
+        max_request_in_minute: config.max_request_in_minute,
+      }];
+      rate_limits.extend(config.rate_limits.iter().cloned());
+
+      let mut initialized = vec![];
+      for rate_limit in rate_limits {
+        let rate = NonZeroU32::new(rate_limit.max_request_in_minute.max(1))
+          .ok_or_else(|| PluginError::Other("Invalid rate limit value".to_string()))?;
+
+        initialized.push((rate_limit.key, Limiter::new(rate)));
+        // TODO: change to logging
+        println!(
+          "Rate limiter for {:?} key initialized with {} requests per minute.",
+          rate_limit.key, rate_limit.max_request_in_minute
+        );
+      }
 
-      *limiter = Some(RateLimiter::keyed(Quota::per_minute(rate)));
-      // TODO: change to logging
-      println!(
-        "Rate limiter initialized with {} requests per minute.",
-        config.max_request_in_minute
-      );
+      *limiters = Some(initialized);
     }
 
     Ok(())
@@ -156,3 +1024,112 @@ impl Plugin for ZulipPlugin {
 }
 
 export!(ZulipPlugin with_types_in octabot_rust_sdk::wit);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limiter(per_minute: u32) -> Limiter {
+    Limiter::new(NonZeroU32::new(per_minute).unwrap())
+  }
+
+  fn time(value: &str) -> NaiveTime {
+    ZulipPlugin::parse_time(value).unwrap()
+  }
+
+  fn quiet_hours(start: &str, end: &str) -> QuietHours {
+    QuietHours {
+      destination: Destination {
+        channel: "alerts".to_string(),
+        topic: None,
+      },
+      start: start.to_string(),
+      end: end.to_string(),
+    }
+  }
+
+  #[test]
+  fn limiter_allows_burst_up_to_quota() {
+    let mut limiter = limiter(3);
+    let now = Instant::now();
+    for _ in 0..3 {
+      assert!(limiter.wait_time("task", now).is_zero());
+      limiter.take("task", now);
+    }
+
+    assert_eq!(limiter.wait_time("task", now), Duration::from_secs(20));
+    assert!(limiter.wait_time("other", now).is_zero());
+  }
+
+  #[test]
+  fn limiter_refills_over_time() {
+    let mut limiter = limiter(6);
+    let now = Instant::now();
+    for _ in 0..6 {
+      limiter.take("task", now);
+    }
+
+    assert_eq!(
+      limiter.wait_time("task", now + Duration::from_secs(4)),
+      Duration::from_secs(6)
+    );
+    assert!(limiter.wait_time("task", now + Duration::from_secs(10)).is_zero());
+
+    // Refill is capped at the quota
+    let later = now + Duration::from_secs(3600);
+    for _ in 0..6 {
+      assert!(limiter.wait_time("task", later).is_zero());
+      limiter.take("task", later);
+    }
+    assert!(!limiter.wait_time("task", later).is_zero());
+  }
+
+  #[test]
+  fn check_limits_takes_tokens_only_when_all_allow() {
+    let mut limiters = vec![(LimitKey::Task, limiter(5)), (LimitKey::Stream, limiter(1))];
+    let now = Instant::now();
+
+    assert!(ZulipPlugin::check_limits(&mut limiters, "task", Some(("alerts", "ci")), now).is_ok());
+    assert!(ZulipPlugin::check_limits(&mut limiters, "task", Some(("alerts", "ci")), now).is_err());
+    assert_eq!(limiters[0].1.tokens("task", now), 4.0);
+
+    assert!(ZulipPlugin::check_limits(&mut limiters, "task", Some(("builds", "ci")), now).is_ok());
+    assert!(ZulipPlugin::check_limits(&mut limiters, "task", None, now).is_ok());
+    assert_eq!(limiters[0].1.tokens("task", now), 2.0);
+  }
+
+  #[test]
+  fn limit_keys() {
+    let destination = Some(("alerts", "ci"));
+    assert_eq!(LimitKey::Global.key("task", destination).as_deref(), Some("global"));
+    assert_eq!(LimitKey::Task.key("task", None).as_deref(), Some("task"));
+    assert_eq!(LimitKey::Stream.key("task", destination).as_deref(), Some("alerts"));
+    assert_eq!(
+      LimitKey::StreamTopic.key("task", destination).as_deref(),
+      Some("alerts/ci")
+    );
+    assert_eq!(LimitKey::Stream.key("task", None), None);
+    assert_eq!(LimitKey::StreamTopic.key("task", None), None);
+  }
+
+  #[test]
+  fn quiet_hours_within_day() {
+    let quiet_hours = quiet_hours("09:00", "17:00");
+    assert!(quiet_hours.contains(time("09:00")).unwrap());
+    assert!(quiet_hours.contains(time("16:59")).unwrap());
+    assert!(!quiet_hours.contains(time("17:00")).unwrap());
+    assert!(!quiet_hours.contains(time("08:59")).unwrap());
+  }
+
+  #[test]
+  fn quiet_hours_wrap_around_midnight() {
+    let quiet_hours = quiet_hours("22:00", "08:00");
+    assert!(quiet_hours.contains(time("22:00")).unwrap());
+    assert!(quiet_hours.contains(time("23:30")).unwrap());
+    assert!(quiet_hours.contains(time("00:00")).unwrap());
+    assert!(quiet_hours.contains(time("07:59")).unwrap());
+    assert!(!quiet_hours.contains(time("08:00")).unwrap());
+    assert!(!quiet_hours.contains(time("12:00")).unwrap());
+    assert!(!quiet_hours.contains(time("21:59")).unwrap());
+  }
+}